@@ -0,0 +1,125 @@
+use smithay::{
+	backend::{
+		allocator::Fourcc,
+		renderer::{
+			damage::OutputDamageTracker,
+			element::{
+				surface::{render_elements_from_surface_tree, WaylandSurfaceRenderElement},
+				Kind,
+			},
+			Bind, Color32F, ExportMem, ImportAll, Offscreen, Renderer, TextureMapping,
+		},
+	},
+	utils::{Buffer, Rectangle, Transform},
+};
+
+use crate::window;
+
+// 8-bit RGBA pixels with premultiplied alpha, top row first
+#[derive(Clone, Debug)]
+pub struct RgbaImage {
+	pub width: u32,
+	pub height: u32,
+	pub data: Vec<u8>,
+}
+
+impl RgbaImage {
+	#[cfg(feature = "png")]
+	pub fn encode_png(&self) -> anyhow::Result<Vec<u8>> {
+		let mut out = Vec::new();
+
+		let mut encoder = png::Encoder::new(&mut out, self.width, self.height);
+		encoder.set_color(png::ColorType::Rgba);
+		encoder.set_depth(png::BitDepth::Eight);
+
+		let mut writer = encoder.write_header()?;
+		writer.write_image_data(&self.data)?;
+		writer.finish()?;
+
+		Ok(out)
+	}
+}
+
+// Copies the currently bound render target
+pub fn read_framebuffer<R>(renderer: &mut R, width: u32, height: u32) -> anyhow::Result<RgbaImage>
+where
+	R: ExportMem,
+{
+	let region: Rectangle<i32, Buffer> =
+		Rectangle::from_loc_and_size((0, 0), (width as i32, height as i32));
+
+	let mapping = renderer
+		.copy_framebuffer(region, Fourcc::Abgr8888)
+		.map_err(|e| anyhow::anyhow!("Failed to copy framebuffer: {:?}", e))?;
+	let flipped = mapping.flipped();
+
+	let pixels = renderer
+		.map_texture(&mapping)
+		.map_err(|e| anyhow::anyhow!("Failed to map framebuffer copy: {:?}", e))?;
+
+	let stride = width as usize * 4;
+	let data = if flipped {
+		pixels
+			.chunks_exact(stride)
+			.rev()
+			.flatten()
+			.copied()
+			.collect()
+	} else {
+		pixels.to_vec()
+	};
+
+	Ok(RgbaImage {
+		width,
+		height,
+		data,
+	})
+}
+
+// Renders the toplevel and its popups into an offscreen buffer of the window size.
+// Popups extending past the window are cut off.
+pub fn render_window<R, T>(renderer: &mut R, window: &window::Window) -> anyhow::Result<RgbaImage>
+where
+	R: Renderer + ImportAll + ExportMem + Offscreen<T> + Bind<T>,
+	R::TextureId: Clone + 'static,
+{
+	let width = window.size_x.max(1);
+	let height = window.size_y.max(1);
+
+	let buffer = renderer
+		.create_buffer(Fourcc::Abgr8888, (width as i32, height as i32).into())
+		.map_err(|e| anyhow::anyhow!("Failed to create offscreen buffer: {:?}", e))?;
+	renderer
+		.bind(buffer)
+		.map_err(|e| anyhow::anyhow!("Failed to bind offscreen buffer: {:?}", e))?;
+
+	let mut elements: Vec<WaylandSurfaceRenderElement<R>> = Vec::new();
+
+	for popup in window.popups.iter().rev() {
+		elements.extend(render_elements_from_surface_tree(
+			renderer,
+			popup.surface.wl_surface(),
+			popup.get_surface_pos(),
+			1.0,
+			1.0,
+			Kind::Unspecified,
+		));
+	}
+
+	elements.extend(render_elements_from_surface_tree(
+		renderer,
+		window.toplevel.wl_surface(),
+		(0, 0),
+		1.0,
+		1.0,
+		Kind::Unspecified,
+	));
+
+	let mut damage_tracker =
+		OutputDamageTracker::new((width as i32, height as i32), 1.0, Transform::Normal);
+	damage_tracker
+		.render_output(renderer, 0, &elements, Color32F::TRANSPARENT)
+		.map_err(|e| anyhow::anyhow!("Failed to render window: {:?}", e))?;
+
+	read_framebuffer(renderer, width, height)
+}
@@ -3,20 +3,21 @@ use std::{io::Read, os::unix::net::UnixStream, sync::Arc};
 use smithay::{
 	backend::input::Keycode,
 	input::{keyboard::KeyboardHandle, pointer::PointerHandle},
-	reexports::wayland_server,
+	reexports::wayland_server::{self, backend::ClientId},
 	utils::SerialCounter,
 };
 
 use crate::{
 	comp::{self},
 	display,
-	wayvr::WaylandEnv,
+	event_queue::SyncEventQueue,
+	wayvr::{WayVREvent, WaylandEnv},
 };
 
 pub struct WayVRClient {
 	pub client: wayland_server::Client,
 	pub display_handle: display::DisplayHandle,
-	pub pid: i32,
+	pub pid: u32,
 }
 
 pub struct WayVRManager {
@@ -28,11 +29,12 @@ pub struct WayVRManager {
 
 	display: wayland_server::Display<comp::Application>,
 	listener: wayland_server::ListeningSocket,
+	events: SyncEventQueue<WayVREvent>,
 
 	pub clients: Vec<WayVRClient>,
 }
 
-fn get_display_auth_from_pid(pid: i32) -> anyhow::Result<String> {
+fn get_display_auth_from_pid(pid: u32) -> anyhow::Result<String> {
 	let path = format!("/proc/{}/environ", pid);
 	let mut env_data = String::new();
 	std::fs::File::open(path)?.read_to_string(&mut env_data)?;
@@ -56,6 +58,7 @@ impl WayVRManager {
 		display: wayland_server::Display<comp::Application>,
 		seat_keyboard: KeyboardHandle<comp::Application>,
 		seat_pointer: PointerHandle<comp::Application>,
+		events: SyncEventQueue<WayVREvent>,
 	) -> anyhow::Result<Self> {
 		let (wayland_env, listener) = create_wayland_listener()?;
 
@@ -65,6 +68,7 @@ impl WayVRManager {
 			seat_keyboard,
 			seat_pointer,
 			listener,
+			events,
 			wayland_env,
 			serial_counter: SerialCounter::new(),
 			clients: Vec::new(),
@@ -79,11 +83,18 @@ impl WayVRManager {
 		let client = self
 			.display
 			.handle()
-			.insert_client(stream, Arc::new(comp::ClientState::default()))
+			.insert_client(
+				stream,
+				Arc::new(comp::ClientState::new(
+					self.state.queue_client_disconnected.clone(),
+				)),
+			)
 			.unwrap();
 
 		let creds = client.get_credentials(&self.display.handle())?;
-		let auth_key = get_display_auth_from_pid(creds.pid)?;
+		// Same type as std::process::Child::id, reported with WayVREvent::ProcessExited
+		let pid = creds.pid as u32;
+		let auth_key = get_display_auth_from_pid(pid)?;
 
 		for (idx, cell) in displays.vec.iter().enumerate() {
 			if let Some(cell) = &cell {
@@ -94,7 +105,11 @@ impl WayVRManager {
 					self.clients.push(WayVRClient {
 						client,
 						display_handle,
-						pid: creds.pid,
+						pid,
+					});
+					self.events.send(WayVREvent::ClientConnected {
+						display: display_handle,
+						pid,
 					});
 					return Ok(());
 				}
@@ -120,11 +135,43 @@ impl WayVRManager {
 		}
 
 		self.display.dispatch_clients(&mut self.state)?;
-		self.display.flush_clients()?;
+		self.flush_clients()
+	}
 
+	// Sends out queued events, tick_wayland does this after dispatching
+	pub fn flush_clients(&mut self) -> anyhow::Result<()> {
+		self.display.flush_clients()?;
 		Ok(())
 	}
 
+	pub fn display_handle(&self) -> wayland_server::DisplayHandle {
+		self.display.handle()
+	}
+
+	// Only clients which passed the display auth key check
+	pub fn is_client_authorized(&self, client_id: &ClientId) -> bool {
+		self
+			.clients
+			.iter()
+			.any(|client| client.client.id() == *client_id)
+	}
+
+	pub fn remove_client(&mut self, client_id: &ClientId) {
+		let Some(idx) = self
+			.clients
+			.iter()
+			.position(|client| client.client.id() == *client_id)
+		else {
+			return;
+		};
+
+		let client = self.clients.remove(idx);
+		self.events.send(WayVREvent::ClientDisconnected {
+			display: client.display_handle,
+			pid: client.pid,
+		});
+	}
+
 	pub fn send_key(&mut self, virtual_key: u32, down: bool) {
 		let state = if down {
 			smithay::backend::input::KeyState::Pressed
@@ -1,18 +1,28 @@
-use smithay::backend::renderer::utils::on_commit_buffer_handler;
+use smithay::backend::allocator::dmabuf::Dmabuf;
+use smithay::backend::renderer::utils::{on_commit_buffer_handler, with_renderer_surface_state};
 use smithay::input::{Seat, SeatHandler, SeatState};
 use smithay::reexports::wayland_protocols::xdg::shell::server::xdg_toplevel;
 use smithay::reexports::wayland_server::protocol::{wl_buffer, wl_seat, wl_surface};
 use smithay::reexports::wayland_server::{self, Resource};
 use smithay::wayland::buffer::BufferHandler;
+use smithay::wayland::dmabuf::{DmabufGlobal, DmabufHandler, DmabufState, ImportNotifier};
+use smithay::wayland::fractional_scale::FractionalScaleManagerState;
+use smithay::wayland::output::OutputManagerState;
+use smithay::wayland::shell::kde::decoration::KdeDecorationState;
+use smithay::wayland::shell::xdg::decoration::XdgDecorationState;
 use smithay::wayland::shm::{ShmHandler, ShmState};
+use smithay::wayland::viewporter::ViewporterState;
 use smithay::{
-	delegate_compositor, delegate_data_device, delegate_seat, delegate_shm, delegate_xdg_shell,
+	delegate_compositor, delegate_data_device, delegate_dmabuf, delegate_fractional_scale,
+	delegate_kde_decoration, delegate_output, delegate_seat, delegate_shm, delegate_viewporter,
+	delegate_xdg_decoration, delegate_xdg_shell,
 };
+use std::cell::Cell;
 use std::os::fd::OwnedFd;
 
 use smithay::utils::Serial;
 use smithay::wayland::compositor::{
-	self, with_surface_tree_downward, SurfaceAttributes, TraversalAction,
+	self, with_states, with_surface_tree_downward, SurfaceAttributes, TraversalAction,
 };
 
 use smithay::wayland::selection::data_device::{
@@ -20,13 +30,14 @@ use smithay::wayland::selection::data_device::{
 };
 use smithay::wayland::selection::SelectionHandler;
 use smithay::wayland::shell::xdg::{
-	PopupSurface, PositionerState, ToplevelSurface, XdgShellHandler, XdgShellState,
+	PopupSurface, PositionerState, ToplevelSurface, XdgShellHandler, XdgShellState, XDG_TOPLEVEL_ROLE,
 };
 use wayland_server::backend::{ClientData, ClientId, DisconnectReason};
 use wayland_server::protocol::wl_surface::WlSurface;
 use wayland_server::Client;
 
 use crate::event_queue::SyncEventQueue;
+use crate::{decoration, screencopy};
 
 pub struct Application {
 	pub compositor: compositor::CompositorState,
@@ -34,8 +45,31 @@ pub struct Application {
 	pub seat_state: SeatState<Application>,
 	pub shm: ShmState,
 	pub data_device: DataDeviceState,
+	pub output_manager: OutputManagerState,
+	pub fractional_scale: FractionalScaleManagerState,
+	pub viewporter: ViewporterState,
+	pub xdg_decoration: XdgDecorationState,
+	pub kde_decoration: KdeDecorationState,
+	pub screencopy: Option<screencopy::ScreencopyState>,
+	pub dmabuf_state: DmabufState,
+	pub dmabuf_global: Option<DmabufGlobal>,
 
 	pub queue_new_toplevel: SyncEventQueue<(ClientId, ToplevelSurface)>,
+	pub queue_new_popup: SyncEventQueue<(PopupSurface, PositionerState)>,
+	pub queue_popup_grab: SyncEventQueue<PopupSurface>,
+	pub queue_popup_reposition: SyncEventQueue<(PopupSurface, PositionerState, u32)>,
+	pub queue_popup_destroyed: SyncEventQueue<PopupSurface>,
+	pub queue_toplevel_destroyed: SyncEventQueue<ToplevelSurface>,
+	pub queue_toplevel_mapping: SyncEventQueue<(WlSurface, bool)>, // true if mapped, false if unmapped
+	pub queue_client_disconnected: SyncEventQueue<ClientId>,
+	pub queue_title_changed: SyncEventQueue<ToplevelSurface>,
+	pub queue_app_id_changed: SyncEventQueue<ToplevelSurface>,
+	pub queue_focus_changed: SyncEventQueue<Option<WlSurface>>,
+	pub queue_move_request: SyncEventQueue<(ToplevelSurface, Serial)>,
+	pub queue_resize_request: SyncEventQueue<(ToplevelSurface, Serial, xdg_toplevel::ResizeEdge)>,
+	pub queue_screencopy: SyncEventQueue<screencopy::ScreencopyRequest>,
+	pub queue_dmabuf_import: SyncEventQueue<(Dmabuf, ImportNotifier)>,
+	pub queue_decoration: SyncEventQueue<decoration::ClientDecoration>,
 }
 
 impl compositor::CompositorHandler for Application {
@@ -52,9 +86,39 @@ impl compositor::CompositorHandler for Application {
 
 	fn commit(&mut self, surface: &WlSurface) {
 		on_commit_buffer_handler::<Self>(surface);
+
+		// Toplevels are mapped by a commit with a buffer attached, and unmapped by one with a null buffer
+		if compositor::get_role(surface) != Some(XDG_TOPLEVEL_ROLE) {
+			return;
+		}
+
+		let has_buffer =
+			with_renderer_surface_state(surface, |state| state.buffer().is_some()).unwrap_or(false);
+
+		let changed = with_states(surface, |states| {
+			states
+				.data_map
+				.insert_if_missing(|| ToplevelMapped(Cell::new(false)));
+			states
+				.data_map
+				.get::<ToplevelMapped>()
+				.unwrap()
+				.0
+				.replace(has_buffer)
+				!= has_buffer
+		});
+
+		if changed {
+			self
+				.queue_toplevel_mapping
+				.send((surface.clone(), has_buffer));
+		}
 	}
 }
 
+// Set while the toplevel has a buffer attached
+struct ToplevelMapped(Cell<bool>);
+
 impl SeatHandler for Application {
 	type KeyboardFocus = WlSurface;
 	type PointerFocus = WlSurface;
@@ -64,7 +128,9 @@ impl SeatHandler for Application {
 		&mut self.seat_state
 	}
 
-	fn focus_changed(&mut self, _seat: &Seat<Self>, _focused: Option<&WlSurface>) {}
+	fn focus_changed(&mut self, _seat: &Seat<Self>, focused: Option<&WlSurface>) {
+		self.queue_focus_changed.send(focused.cloned());
+	}
 	fn cursor_image(
 		&mut self,
 		_seat: &Seat<Self>,
@@ -93,9 +159,18 @@ impl SelectionHandler for Application {
 	type SelectionUserData = ();
 }
 
-#[derive(Default)]
 pub struct ClientState {
 	compositor_state: compositor::CompositorClientState,
+	queue_disconnected: SyncEventQueue<ClientId>,
+}
+
+impl ClientState {
+	pub fn new(queue_disconnected: SyncEventQueue<ClientId>) -> Self {
+		Self {
+			compositor_state: Default::default(),
+			queue_disconnected,
+		}
+	}
 }
 
 impl ClientData for ClientState {
@@ -109,6 +184,7 @@ impl ClientData for ClientState {
 			client_id,
 			reason
 		);
+		self.queue_disconnected.send(client_id);
 	}
 }
 
@@ -133,21 +209,60 @@ impl XdgShellHandler for Application {
 		surface.send_configure();
 	}
 
-	fn new_popup(&mut self, _surface: PopupSurface, _positioner: PositionerState) {
-		// Handle popup creation here
+	fn toplevel_destroyed(&mut self, surface: ToplevelSurface) {
+		self.queue_toplevel_destroyed.send(surface);
 	}
 
-	fn grab(&mut self, _surface: PopupSurface, _seat: wl_seat::WlSeat, _serial: Serial) {
-		// Handle popup grab here
+	fn move_request(&mut self, surface: ToplevelSurface, _seat: wl_seat::WlSeat, serial: Serial) {
+		self.queue_move_request.send((surface, serial));
 	}
 
-	fn reposition_request(
+	fn resize_request(
 		&mut self,
-		_surface: PopupSurface,
-		_positioner: PositionerState,
-		_token: u32,
+		surface: ToplevelSurface,
+		_seat: wl_seat::WlSeat,
+		serial: Serial,
+		edges: xdg_toplevel::ResizeEdge,
 	) {
-		// Handle popup reposition here
+		self.queue_resize_request.send((surface, serial, edges));
+	}
+
+	fn title_changed(&mut self, surface: ToplevelSurface) {
+		self.queue_title_changed.send(surface);
+	}
+
+	fn app_id_changed(&mut self, surface: ToplevelSurface) {
+		self.queue_app_id_changed.send(surface);
+	}
+
+	fn new_popup(&mut self, surface: PopupSurface, positioner: PositionerState) {
+		// Positioned and configured in WayVR::tick_events, display bounds are needed for that
+		self.queue_new_popup.send((surface, positioner));
+	}
+
+	fn grab(&mut self, surface: PopupSurface, _seat: wl_seat::WlSeat, _serial: Serial) {
+		self.queue_popup_grab.send(surface);
+	}
+
+	fn reposition_request(&mut self, surface: PopupSurface, positioner: PositionerState, token: u32) {
+		self
+			.queue_popup_reposition
+			.send((surface, positioner, token));
+	}
+
+	fn popup_destroyed(&mut self, surface: PopupSurface) {
+		self.queue_popup_destroyed.send(surface);
+	}
+}
+
+impl DmabufHandler for Application {
+	fn dmabuf_state(&mut self) -> &mut DmabufState {
+		&mut self.dmabuf_state
+	}
+
+	fn dmabuf_imported(&mut self, _global: &DmabufGlobal, dmabuf: Dmabuf, notifier: ImportNotifier) {
+		// Checked against the renderer in WayVR::tick_events
+		self.queue_dmabuf_import.send((dmabuf, notifier));
 	}
 }
 
@@ -162,6 +277,12 @@ delegate_compositor!(Application);
 delegate_shm!(Application);
 delegate_seat!(Application);
 delegate_data_device!(Application);
+delegate_output!(Application);
+delegate_dmabuf!(Application);
+delegate_fractional_scale!(Application);
+delegate_viewporter!(Application);
+delegate_xdg_decoration!(Application);
+delegate_kde_decoration!(Application);
 
 pub fn send_frames_surface_tree(surface: &wl_surface::WlSurface, time: u32) {
 	with_surface_tree_downward(
@@ -0,0 +1,474 @@
+// Server-side decorations: xdg-decoration and KDE server decoration negotiation,
+// plus the title bar drawn by the compositor above decorated windows.
+
+use smithay::{
+	backend::{
+		allocator::Fourcc,
+		renderer::{
+			element::{memory::MemoryRenderBuffer, memory::MemoryRenderBufferRenderElement, Kind},
+			ImportMem, Renderer,
+		},
+	},
+	reexports::{
+		wayland_protocols::xdg::decoration::zv1::server::zxdg_toplevel_decoration_v1,
+		wayland_protocols_misc::server_decoration::server::{
+			org_kde_kwin_server_decoration::OrgKdeKwinServerDecoration,
+			org_kde_kwin_server_decoration_manager::Mode as KdeMode,
+		},
+		wayland_server::{protocol::wl_surface::WlSurface, Resource, WEnum},
+	},
+	utils::{Physical, Point, Transform},
+	wayland::shell::{
+		kde::decoration::{KdeDecorationHandler, KdeDecorationState},
+		xdg::{decoration::XdgDecorationHandler, ToplevelSurface},
+	},
+};
+
+use crate::comp::Application;
+
+// Logical pixels
+pub const TITLE_BAR_HEIGHT: u32 = 24;
+const BUTTON_WIDTH: u32 = 32;
+const ICON_SIZE: u32 = 10;
+const TEXT_PADDING: u32 = 8;
+const GLYPH_SCALE: u32 = 2;
+
+const COLOR_BACKGROUND: [u8; 4] = [0x2b, 0x2b, 0x2b, 0xff];
+const COLOR_TEXT: [u8; 4] = [0xe0, 0xe0, 0xe0, 0xff];
+const COLOR_BUTTON_HOVER: [u8; 4] = [0x48, 0x48, 0x48, 0xff];
+const COLOR_CLOSE_HOVER: [u8; 4] = [0xc4, 0x2b, 0x1c, 0xff];
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum DecorationMode {
+	// Title bar drawn by the compositor
+	ServerSide,
+	// Clients draw their own decorations
+	ClientSide,
+	// No decorations at all, clients are asked not to draw them either
+	None,
+}
+
+// Decoration object of a client which negotiates decorations
+#[derive(Clone)]
+pub enum ClientDecoration {
+	Xdg(ToplevelSurface),
+	Kde(WlSurface, OrgKdeKwinServerDecoration),
+}
+
+impl ClientDecoration {
+	pub fn wl_surface(&self) -> &WlSurface {
+		match self {
+			Self::Xdg(toplevel) => toplevel.wl_surface(),
+			Self::Kde(surface, _) => surface,
+		}
+	}
+
+	pub fn alive(&self) -> bool {
+		match self {
+			Self::Xdg(toplevel) => toplevel.alive(),
+			Self::Kde(surface, decoration) => surface.alive() && decoration.is_alive(),
+		}
+	}
+
+	// Tells the client whether it should draw its own decorations
+	pub fn send_mode(&self, mode: DecorationMode) {
+		let server_side = mode != DecorationMode::ClientSide;
+
+		match self {
+			Self::Xdg(toplevel) => {
+				toplevel.with_pending_state(|state| {
+					state.decoration_mode = Some(if server_side {
+						zxdg_toplevel_decoration_v1::Mode::ServerSide
+					} else {
+						zxdg_toplevel_decoration_v1::Mode::ClientSide
+					});
+				});
+				toplevel.send_configure();
+			}
+			Self::Kde(_, decoration) => {
+				decoration.mode(if server_side {
+					KdeMode::Server
+				} else {
+					KdeMode::Client
+				});
+			}
+		}
+	}
+}
+
+// Every request gets answered with the mode of the display, see WayVR::tick_events
+impl XdgDecorationHandler for Application {
+	fn new_decoration(&mut self, toplevel: ToplevelSurface) {
+		self.queue_decoration.send(ClientDecoration::Xdg(toplevel));
+	}
+
+	fn request_mode(&mut self, toplevel: ToplevelSurface, _mode: zxdg_toplevel_decoration_v1::Mode) {
+		self.queue_decoration.send(ClientDecoration::Xdg(toplevel));
+	}
+
+	fn unset_mode(&mut self, toplevel: ToplevelSurface) {
+		self.queue_decoration.send(ClientDecoration::Xdg(toplevel));
+	}
+}
+
+impl KdeDecorationHandler for Application {
+	fn kde_decoration_state(&self) -> &KdeDecorationState {
+		&self.kde_decoration
+	}
+
+	fn new_decoration(&mut self, surface: &WlSurface, decoration: &OrgKdeKwinServerDecoration) {
+		self
+			.queue_decoration
+			.send(ClientDecoration::Kde(surface.clone(), decoration.clone()));
+	}
+
+	fn request_mode(
+		&mut self,
+		surface: &WlSurface,
+		decoration: &OrgKdeKwinServerDecoration,
+		_mode: WEnum<KdeMode>,
+	) {
+		self
+			.queue_decoration
+			.send(ClientDecoration::Kde(surface.clone(), decoration.clone()));
+	}
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum TitleBarButton {
+	Close,
+	Maximize,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum TitleBarHit {
+	Button(TitleBarButton),
+	// Empty space, drags the window in floating layouts
+	Bar,
+}
+
+// Position relative to the top left corner of the title bar, in logical pixels
+pub fn hit_test(width: u32, x: i32, y: i32) -> Option<TitleBarHit> {
+	if x < 0 || y < 0 || x >= width as i32 || y >= TITLE_BAR_HEIGHT as i32 {
+		return None;
+	}
+
+	let from_right = width as i32 - x;
+	if from_right <= BUTTON_WIDTH as i32 {
+		Some(TitleBarHit::Button(TitleBarButton::Close))
+	} else if from_right <= BUTTON_WIDTH as i32 * 2 {
+		Some(TitleBarHit::Button(TitleBarButton::Maximize))
+	} else {
+		Some(TitleBarHit::Bar)
+	}
+}
+
+// 5x7 glyphs of printable ASCII, one byte per row, bit 4 is the leftmost column
+const GLYPH_WIDTH: u32 = 5;
+const GLYPH_HEIGHT: u32 = 7;
+const GLYPH_ADVANCE: u32 = GLYPH_WIDTH + 1;
+
+#[rustfmt::skip]
+const FONT: [[u8; 7]; 95] = [
+	[0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00], // ' '
+	[0x04, 0x04, 0x04, 0x04, 0x04, 0x00, 0x04], // '!'
+	[0x0a, 0x0a, 0x0a, 0x00, 0x00, 0x00, 0x00], // '"'
+	[0x0a, 0x0a, 0x1f, 0x0a, 0x1f, 0x0a, 0x0a], // '#'
+	[0x04, 0x0f, 0x14, 0x0e, 0x05, 0x1e, 0x04], // '$'
+	[0x18, 0x19, 0x02, 0x04, 0x08, 0x13, 0x03], // '%'
+	[0x0c, 0x12, 0x14, 0x08, 0x15, 0x12, 0x0d], // '&'
+	[0x04, 0x04, 0x04, 0x00, 0x00, 0x00, 0x00], // '''
+	[0x02, 0x04, 0x08, 0x08, 0x08, 0x04, 0x02], // '('
+	[0x08, 0x04, 0x02, 0x02, 0x02, 0x04, 0x08], // ')'
+	[0x00, 0x04, 0x15, 0x0e, 0x15, 0x04, 0x00], // '*'
+	[0x00, 0x04, 0x04, 0x1f, 0x04, 0x04, 0x00], // '+'
+	[0x00, 0x00, 0x00, 0x00, 0x0c, 0x04, 0x08], // ','
+	[0x00, 0x00, 0x00, 0x1f, 0x00, 0x00, 0x00], // '-'
+	[0x00, 0x00, 0x00, 0x00, 0x00, 0x0c, 0x0c], // '.'
+	[0x00, 0x01, 0x02, 0x04, 0x08, 0x10, 0x00], // '/'
+	[0x0e, 0x11, 0x13, 0x15, 0x19, 0x11, 0x0e], // '0'
+	[0x04, 0x0c, 0x04, 0x04, 0x04, 0x04, 0x0e], // '1'
+	[0x0e, 0x11, 0x01, 0x02, 0x04, 0x08, 0x1f], // '2'
+	[0x1f, 0x02, 0x04, 0x02, 0x01, 0x11, 0x0e], // '3'
+	[0x02, 0x06, 0x0a, 0x12, 0x1f, 0x02, 0x02], // '4'
+	[0x1f, 0x10, 0x1e, 0x01, 0x01, 0x11, 0x0e], // '5'
+	[0x06, 0x08, 0x10, 0x1e, 0x11, 0x11, 0x0e], // '6'
+	[0x1f, 0x01, 0x02, 0x04, 0x08, 0x08, 0x08], // '7'
+	[0x0e, 0x11, 0x11, 0x0e, 0x11, 0x11, 0x0e], // '8'
+	[0x0e, 0x11, 0x11, 0x0f, 0x01, 0x02, 0x0c], // '9'
+	[0x00, 0x0c, 0x0c, 0x00, 0x0c, 0x0c, 0x00], // ':'
+	[0x00, 0x0c, 0x0c, 0x00, 0x0c, 0x04, 0x08], // ';'
+	[0x02, 0x04, 0x08, 0x10, 0x08, 0x04, 0x02], // '<'
+	[0x00, 0x00, 0x1f, 0x00, 0x1f, 0x00, 0x00], // '='
+	[0x08, 0x04, 0x02, 0x01, 0x02, 0x04, 0x08], // '>'
+	[0x0e, 0x11, 0x01, 0x02, 0x04, 0x00, 0x04], // '?'
+	[0x0e, 0x11, 0x01, 0x0d, 0x15, 0x15, 0x0e], // '@'
+	[0x0e, 0x11, 0x11, 0x1f, 0x11, 0x11, 0x11], // 'A'
+	[0x1e, 0x11, 0x11, 0x1e, 0x11, 0x11, 0x1e], // 'B'
+	[0x0e, 0x11, 0x10, 0x10, 0x10, 0x11, 0x0e], // 'C'
+	[0x1c, 0x12, 0x11, 0x11, 0x11, 0x12, 0x1c], // 'D'
+	[0x1f, 0x10, 0x10, 0x1e, 0x10, 0x10, 0x1f], // 'E'
+	[0x1f, 0x10, 0x10, 0x1e, 0x10, 0x10, 0x10], // 'F'
+	[0x0e, 0x11, 0x10, 0x17, 0x11, 0x11, 0x0f], // 'G'
+	[0x11, 0x11, 0x11, 0x1f, 0x11, 0x11, 0x11], // 'H'
+	[0x0e, 0x04, 0x04, 0x04, 0x04, 0x04, 0x0e], // 'I'
+	[0x07, 0x02, 0x02, 0x02, 0x02, 0x12, 0x0c], // 'J'
+	[0x11, 0x12, 0x14, 0x18, 0x14, 0x12, 0x11], // 'K'
+	[0x10, 0x10, 0x10, 0x10, 0x10, 0x10, 0x1f], // 'L'
+	[0x11, 0x1b, 0x15, 0x15, 0x11, 0x11, 0x11], // 'M'
+	[0x11, 0x11, 0x19, 0x15, 0x13, 0x11, 0x11], // 'N'
+	[0x0e, 0x11, 0x11, 0x11, 0x11, 0x11, 0x0e], // 'O'
+	[0x1e, 0x11, 0x11, 0x1e, 0x10, 0x10, 0x10], // 'P'
+	[0x0e, 0x11, 0x11, 0x11, 0x15, 0x12, 0x0d], // 'Q'
+	[0x1e, 0x11, 0x11, 0x1e, 0x14, 0x12, 0x11], // 'R'
+	[0x0f, 0x10, 0x10, 0x0e, 0x01, 0x01, 0x1e], // 'S'
+	[0x1f, 0x04, 0x04, 0x04, 0x04, 0x04, 0x04], // 'T'
+	[0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x0e], // 'U'
+	[0x11, 0x11, 0x11, 0x11, 0x11, 0x0a, 0x04], // 'V'
+	[0x11, 0x11, 0x11, 0x15, 0x15, 0x15, 0x0a], // 'W'
+	[0x11, 0x11, 0x0a, 0x04, 0x0a, 0x11, 0x11], // 'X'
+	[0x11, 0x11, 0x11, 0x0a, 0x04, 0x04, 0x04], // 'Y'
+	[0x1f, 0x01, 0x02, 0x04, 0x08, 0x10, 0x1f], // 'Z'
+	[0x0e, 0x08, 0x08, 0x08, 0x08, 0x08, 0x0e], // '['
+	[0x00, 0x10, 0x08, 0x04, 0x02, 0x01, 0x00], // '\'
+	[0x0e, 0x02, 0x02, 0x02, 0x02, 0x02, 0x0e], // ']'
+	[0x04, 0x0a, 0x11, 0x00, 0x00, 0x00, 0x00], // '^'
+	[0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x1f], // '_'
+	[0x08, 0x04, 0x02, 0x00, 0x00, 0x00, 0x00], // '`'
+	[0x00, 0x00, 0x0e, 0x01, 0x0f, 0x11, 0x0f], // 'a'
+	[0x10, 0x10, 0x16, 0x19, 0x11, 0x11, 0x1e], // 'b'
+	[0x00, 0x00, 0x0e, 0x10, 0x10, 0x11, 0x0e], // 'c'
+	[0x01, 0x01, 0x0d, 0x13, 0x11, 0x11, 0x0f], // 'd'
+	[0x00, 0x00, 0x0e, 0x11, 0x1f, 0x10, 0x0e], // 'e'
+	[0x06, 0x09, 0x08, 0x1c, 0x08, 0x08, 0x08], // 'f'
+	[0x00, 0x0f, 0x11, 0x11, 0x0f, 0x01, 0x0e], // 'g'
+	[0x10, 0x10, 0x16, 0x19, 0x11, 0x11, 0x11], // 'h'
+	[0x04, 0x00, 0x0c, 0x04, 0x04, 0x04, 0x0e], // 'i'
+	[0x02, 0x00, 0x06, 0x02, 0x02, 0x12, 0x0c], // 'j'
+	[0x10, 0x10, 0x12, 0x14, 0x18, 0x14, 0x12], // 'k'
+	[0x0c, 0x04, 0x04, 0x04, 0x04, 0x04, 0x0e], // 'l'
+	[0x00, 0x00, 0x1a, 0x15, 0x15, 0x11, 0x11], // 'm'
+	[0x00, 0x00, 0x16, 0x19, 0x11, 0x11, 0x11], // 'n'
+	[0x00, 0x00, 0x0e, 0x11, 0x11, 0x11, 0x0e], // 'o'
+	[0x00, 0x00, 0x1e, 0x11, 0x1e, 0x10, 0x10], // 'p'
+	[0x00, 0x00, 0x0d, 0x13, 0x0f, 0x01, 0x01], // 'q'
+	[0x00, 0x00, 0x16, 0x19, 0x10, 0x10, 0x10], // 'r'
+	[0x00, 0x00, 0x0e, 0x10, 0x0e, 0x01, 0x1e], // 's'
+	[0x08, 0x08, 0x1c, 0x08, 0x08, 0x09, 0x06], // 't'
+	[0x00, 0x00, 0x11, 0x11, 0x11, 0x13, 0x0d], // 'u'
+	[0x00, 0x00, 0x11, 0x11, 0x11, 0x0a, 0x04], // 'v'
+	[0x00, 0x00, 0x11, 0x11, 0x15, 0x15, 0x0a], // 'w'
+	[0x00, 0x00, 0x11, 0x0a, 0x04, 0x0a, 0x11], // 'x'
+	[0x00, 0x00, 0x11, 0x11, 0x0f, 0x01, 0x0e], // 'y'
+	[0x00, 0x00, 0x1f, 0x02, 0x04, 0x08, 0x1f], // 'z'
+	[0x02, 0x04, 0x04, 0x08, 0x04, 0x04, 0x02], // '{'
+	[0x04, 0x04, 0x04, 0x04, 0x04, 0x04, 0x04], // '|'
+	[0x08, 0x04, 0x04, 0x02, 0x04, 0x04, 0x08], // '}'
+	[0x00, 0x00, 0x08, 0x15, 0x02, 0x00, 0x00], // '~'
+];
+
+fn get_glyph(c: char) -> &'static [u8; 7] {
+	let idx = match c {
+		' '..='~' => c as usize - ' ' as usize,
+		_ => '?' as usize - ' ' as usize,
+	};
+	&FONT[idx]
+}
+
+// RGBA pixels drawn at an integer buffer scale
+struct Canvas {
+	data: Vec<u8>,
+	width: u32,
+	height: u32,
+	scale: u32,
+}
+
+impl Canvas {
+	fn new(width: u32, height: u32, scale: u32) -> Self {
+		let width = width * scale;
+		let height = height * scale;
+		Self {
+			data: vec![0; (width * height * 4) as usize],
+			width,
+			height,
+			scale,
+		}
+	}
+
+	// Logical coordinates, clipped to the canvas
+	fn fill_rect(&mut self, x: u32, y: u32, w: u32, h: u32, color: [u8; 4]) {
+		let x_end = ((x + w) * self.scale).min(self.width);
+		let y_end = ((y + h) * self.scale).min(self.height);
+
+		for py in (y * self.scale)..y_end {
+			for px in (x * self.scale)..x_end {
+				let idx = ((py * self.width + px) * 4) as usize;
+				self.data[idx..idx + 4].copy_from_slice(&color);
+			}
+		}
+	}
+
+	// Physical coordinates, for lines thinner than a logical pixel
+	fn set_pixel(&mut self, x: u32, y: u32, color: [u8; 4]) {
+		if x < self.width && y < self.height {
+			let idx = ((y * self.width + x) * 4) as usize;
+			self.data[idx..idx + 4].copy_from_slice(&color);
+		}
+	}
+
+	// Characters which don't fit before max_x are cut off
+	fn draw_text(&mut self, text: &str, x: u32, y: u32, max_x: u32, color: [u8; 4]) {
+		let mut cursor = x;
+
+		for c in text.chars() {
+			if cursor + GLYPH_WIDTH * GLYPH_SCALE > max_x {
+				break;
+			}
+
+			for (row, bits) in get_glyph(c).iter().enumerate() {
+				for col in 0..GLYPH_WIDTH {
+					if bits & (1 << (GLYPH_WIDTH - 1 - col)) != 0 {
+						self.fill_rect(
+							cursor + col * GLYPH_SCALE,
+							y + row as u32 * GLYPH_SCALE,
+							GLYPH_SCALE,
+							GLYPH_SCALE,
+							color,
+						);
+					}
+				}
+			}
+
+			cursor += GLYPH_ADVANCE * GLYPH_SCALE;
+		}
+	}
+
+	fn draw_close_icon(&mut self, x: u32, y: u32, color: [u8; 4]) {
+		let size = ICON_SIZE * self.scale;
+		let (x, y) = (x * self.scale, y * self.scale);
+
+		for i in 0..size {
+			for t in 0..self.scale {
+				self.set_pixel(x + i + t, y + i, color);
+				self.set_pixel(x + i + t, y + size - 1 - i, color);
+			}
+		}
+	}
+
+	fn draw_maximize_icon(&mut self, x: u32, y: u32, color: [u8; 4]) {
+		self.fill_rect(x, y, ICON_SIZE, 1, color);
+		self.fill_rect(x, y + ICON_SIZE - 1, ICON_SIZE, 1, color);
+		self.fill_rect(x, y, 1, ICON_SIZE, color);
+		self.fill_rect(x + ICON_SIZE - 1, y, 1, ICON_SIZE, color);
+	}
+}
+
+#[derive(Clone, PartialEq)]
+struct TitleBarState {
+	title: String,
+	width: u32,
+	scale: u32,
+	hovered: Option<TitleBarButton>,
+}
+
+// Title bar of a single window, redrawn only if something visible changed
+pub struct TitleBar {
+	buffer: MemoryRenderBuffer,
+	state: Option<TitleBarState>,
+}
+
+impl TitleBar {
+	pub fn new() -> Self {
+		Self {
+			buffer: MemoryRenderBuffer::new(Fourcc::Abgr8888, (1, 1), 1, Transform::Normal, None),
+			state: None,
+		}
+	}
+
+	// Width is logical, the bar is drawn at the display scale rounded up
+	pub fn update(&mut self, title: &str, width: u32, scale: f64, hovered: Option<TitleBarButton>) {
+		let state = TitleBarState {
+			title: String::from(title),
+			width: width.max(1),
+			scale: (scale.ceil() as u32).max(1),
+			hovered,
+		};
+
+		if self.state.as_ref() == Some(&state) {
+			return;
+		}
+
+		let mut canvas = Canvas::new(state.width, TITLE_BAR_HEIGHT, state.scale);
+		canvas.fill_rect(0, 0, state.width, TITLE_BAR_HEIGHT, COLOR_BACKGROUND);
+
+		let buttons_x = state.width.saturating_sub(BUTTON_WIDTH * 2);
+		let icon_y = (TITLE_BAR_HEIGHT - ICON_SIZE) / 2;
+		let icon_offset = (BUTTON_WIDTH - ICON_SIZE) / 2;
+
+		let maximize_x = buttons_x;
+		let close_x = buttons_x + BUTTON_WIDTH;
+
+		if state.hovered == Some(TitleBarButton::Maximize) {
+			canvas.fill_rect(
+				maximize_x,
+				0,
+				BUTTON_WIDTH,
+				TITLE_BAR_HEIGHT,
+				COLOR_BUTTON_HOVER,
+			);
+		}
+		if state.hovered == Some(TitleBarButton::Close) {
+			canvas.fill_rect(
+				close_x,
+				0,
+				BUTTON_WIDTH,
+				TITLE_BAR_HEIGHT,
+				COLOR_CLOSE_HOVER,
+			);
+		}
+
+		canvas.draw_maximize_icon(maximize_x + icon_offset, icon_y, COLOR_TEXT);
+		canvas.draw_close_icon(close_x + icon_offset, icon_y, COLOR_TEXT);
+
+		let text_y = (TITLE_BAR_HEIGHT - GLYPH_HEIGHT * GLYPH_SCALE) / 2;
+		canvas.draw_text(
+			&state.title,
+			TEXT_PADDING,
+			text_y,
+			buttons_x.saturating_sub(TEXT_PADDING),
+			COLOR_TEXT,
+		);
+
+		self.buffer = MemoryRenderBuffer::from_slice(
+			&canvas.data,
+			Fourcc::Abgr8888,
+			(canvas.width as i32, canvas.height as i32),
+			state.scale as i32,
+			Transform::Normal,
+			None,
+		);
+		self.state = Some(state);
+	}
+
+	pub fn render_element<R>(
+		&self,
+		renderer: &mut R,
+		location: Point<i32, Physical>,
+	) -> Option<MemoryRenderBufferRenderElement<R>>
+	where
+		R: Renderer + ImportMem,
+		R::TextureId: Send + Clone + 'static,
+	{
+		MemoryRenderBufferRenderElement::from_buffer(
+			renderer,
+			location.to_f64(),
+			&self.buffer,
+			None,
+			None,
+			None,
+			Kind::Unspecified,
+		)
+		.map_err(|e| log::error!("Failed to upload title bar: {:?}", e))
+		.ok()
+	}
+}
@@ -1,25 +1,47 @@
-use std::{cell::RefCell, rc::Rc};
+use std::{cell::RefCell, os::fd::OwnedFd, rc::Rc};
 
 use smithay::{
 	backend::renderer::{
 		element::{
+			memory::MemoryRenderBufferRenderElement,
 			surface::{render_elements_from_surface_tree, WaylandSurfaceRenderElement},
 			Kind,
 		},
-		gles::{ffi, GlesRenderer, GlesTexture},
-		utils::draw_render_elements,
-		Bind, Color32F, Frame, Renderer,
+		gles::GlesRenderer,
+		sync::SyncPoint,
+		Blit, Color32F, ImportAll, ImportMem, Renderer, TextureFilter,
 	},
 	input,
-	utils::{Logical, Point, Rectangle, Size, Transform},
-	wayland::shell::xdg::ToplevelSurface,
+	reexports::{
+		wayland_protocols::xdg::shell::server::xdg_toplevel,
+		wayland_server::{
+			protocol::{wl_buffer::WlBuffer, wl_surface::WlSurface},
+			Resource,
+		},
+	},
+	utils::{Logical, Physical, Point, Rectangle},
+	wayland::{
+		dmabuf::get_dmabuf,
+		shell::xdg::{PositionerState, ToplevelSurface},
+	},
 };
 
+#[cfg(feature = "pixman")]
+use smithay::backend::renderer::{pixman::PixmanRenderer, Unbind};
+
 use crate::{
-	client::WayVRManager, comp::send_frames_surface_tree, egl_data, gen_id, smithay_wrapper, wayvr,
-	window,
+	capture, client::WayVRManager, comp::send_frames_surface_tree, decoration, egl_data, gen_id,
+	layout, output, render, screencopy, swapchain, wayvr, window,
 };
 
+pub use crate::decoration::DecorationMode;
+
+smithay::render_elements! {
+	DisplayRenderElement<R> where R: ImportAll + ImportMem;
+	Surface=WaylandSurfaceRenderElement<R>,
+	TitleBar=MemoryRenderBufferRenderElement<R>,
+}
+
 fn generate_auth_key() -> String {
 	let uuid = uuid::Uuid::new_v4();
 	uuid.to_string()
@@ -39,72 +61,298 @@ impl Drop for Process {
 struct DisplayWindow {
 	handle: window::WindowHandle,
 	toplevel: ToplevelSurface,
+	visible: bool, // Hidden by the layout
+	// Geometry to return to, Some while the window covers the whole display
+	restore_rect: Option<layout::LayoutRect>,
+	// Set if the client negotiates decorations, others always draw their own
+	decoration: Option<decoration::ClientDecoration>,
+	title_bar: Option<decoration::TitleBar>,
 }
 
-pub struct Display {
-	// Display info stuff
+// Interactive move/resize started by the client, driven by the pointer
+enum PointerGrab {
+	Move {
+		handle: window::WindowHandle,
+		start_cursor: Point<f64, Logical>,
+		start_rect: layout::LayoutRect,
+	},
+	Resize {
+		handle: window::WindowHandle,
+		edges: xdg_toplevel::ResizeEdge,
+		start_cursor: Point<f64, Logical>,
+		start_rect: layout::LayoutRect,
+	},
+}
+
+pub struct DisplayFrame {
+	// Swapchain buffer which received this frame
+	pub buffer_index: usize,
+	// sync_file fd which signals when the GPU finishes rendering this frame.
+	// None if the frame was already finished (shm output or native fences are unsupported).
+	pub sync_fd: Option<OwnedFd>,
+}
+
+// Surface under the cursor, either a toplevel or one of its popups
+struct HoveredSurface {
+	handle: window::WindowHandle,
+	surface: WlSurface,
+	// Surface position on the display
+	pos_x: i32,
+	pos_y: i32,
+}
+
+enum Hovered {
+	Surface(HoveredSurface),
+	TitleBar(window::WindowHandle, decoration::TitleBarHit),
+}
+
+pub use crate::readback::ShmData;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum OutputMode {
+	// dma-buf if the driver supports exporting it, CPU readback otherwise
+	Auto,
+	Dmabuf,
+	Shm,
+}
+
+// Where the host can find rendered display contents.
+// Holds its own duplicates of the buffer fds, which get closed on drop.
+#[derive(Debug)]
+pub enum DisplayOutput {
+	Dmabuf(egl_data::DMAbufData),
+	Shm(ShmData),
+}
+
+impl DisplayOutput {
+	pub fn try_clone(&self) -> anyhow::Result<Self> {
+		Ok(match self {
+			Self::Dmabuf(dmabuf_data) => Self::Dmabuf(dmabuf_data.try_clone()?),
+			Self::Shm(shm_data) => Self::Shm(shm_data.try_clone()?),
+		})
+	}
+}
+
+pub struct DisplayConfig {
 	pub width: u32,
 	pub height: u32,
+	// 1 renders into a single texture, 2 or 3 enable multi-buffering (see WayVR::release_display_buffer).
+	// Shm output always has at least 2 buffers, look up the one of DisplayFrame::buffer_index.
+	pub buffer_count: u32,
+	pub output_mode: OutputMode,
+	// Reported to clients through wl_output, in mHz
+	pub refresh_rate: u32,
+	// Host display density, used to report the physical size. Unknown if None.
+	pub dpi: Option<f32>,
+	// Human-readable wl_output description
+	pub description: Option<String>,
+	// Applies to clients which negotiate decorations
+	pub decoration_mode: DecorationMode,
+}
+
+impl DisplayConfig {
+	pub fn new(width: u32, height: u32) -> Self {
+		Self {
+			width,
+			height,
+			buffer_count: 1,
+			output_mode: OutputMode::Auto,
+			refresh_rate: 60_000,
+			dpi: None,
+			description: None,
+			decoration_mode: DecorationMode::ServerSide,
+		}
+	}
+}
+
+pub struct Display {
+	// Display info stuff
+	width: u32,
+	height: u32,
+	// Windows are laid out in logical coordinates, physical size divided by the scale
+	scale: f64,
+	visible: bool,
 	wm: Rc<RefCell<window::WindowManager>>,
 	displayed_windows: Vec<DisplayWindow>,
 	wayland_env: wayvr::WaylandEnv,
+	layout: Box<dyn layout::Layout>,
+	pointer_grab: Option<PointerGrab>,
+	wl_output: output::WaylandOutput,
+	decoration_mode: DecorationMode,
+	hovered_button: Option<(window::WindowHandle, decoration::TitleBarButton)>,
+	consumed_buttons: Vec<u32>, // Pressed on a title bar, their release isn't sent to clients
 
 	// Render data stuff
-	gles_texture: GlesTexture, // TODO: drop texture
-	egl_image: khronos_egl::Image,
-	egl_data: Rc<egl_data::EGLData>,
-	pub dmabuf_data: egl_data::DMAbufData,
+	swapchain: swapchain::Swapchain,
+	clear_opacity: f32,
 
 	processes: Vec<Process>,
 }
 
-impl Drop for Display {
-	fn drop(&mut self) {
-		let _ = self
-			.egl_data
-			.egl
-			.destroy_image(self.egl_data.display, self.egl_image);
-	}
-}
-
 impl Display {
 	pub fn new(
 		wm: Rc<RefCell<window::WindowManager>>,
-		renderer: &mut GlesRenderer,
-		egl_data: Rc<egl_data::EGLData>,
+		renderer: &mut render::WayVRRenderer,
 		wayland_env: wayvr::WaylandEnv,
-		width: u32,
-		height: u32,
+		wl_output: output::WaylandOutput,
+		config: &DisplayConfig,
 	) -> anyhow::Result<Self> {
-		let tex_format = ffi::RGBA;
-		let internal_format = ffi::RGBA8;
-
-		let tex_id = renderer.with_context(|gl| {
-			smithay_wrapper::create_framebuffer_texture(gl, width, height, tex_format, internal_format)
-		})?;
-
-		let egl_image = egl_data.create_egl_image(tex_id, width, height)?;
-		let dmabuf_data = egl_data.create_dmabuf_data(&egl_image)?;
-
-		let opaque = false;
-		let size = (width as i32, height as i32).into();
-		let gles_texture =
-			unsafe { GlesTexture::from_raw(renderer, Some(tex_format), opaque, tex_id, size) };
+		let swapchain = renderer.create_swapchain(
+			config.width,
+			config.height,
+			config.buffer_count,
+			config.output_mode,
+		)?;
 
 		Ok(Self {
 			wm,
-			width,
-			height,
+			width: config.width,
+			height: config.height,
+			scale: 1.0,
+			visible: true,
 			displayed_windows: Vec::new(),
-			egl_data,
-			dmabuf_data,
-			egl_image,
-			gles_texture,
+			swapchain,
+			clear_opacity: 0.0,
 			wayland_env,
+			layout: Box::new(layout::ColumnsLayout::default()),
+			pointer_grab: None,
+			wl_output,
+			decoration_mode: config.decoration_mode,
+			hovered_button: None,
+			consumed_buttons: Vec::new(),
 			processes: Vec::new(),
 		})
 	}
 
+	pub fn get_size(&self) -> (u32, u32) {
+		(self.width, self.height)
+	}
+
+	// Size available to windows
+	fn get_logical_size(&self) -> (u32, u32) {
+		(
+			(self.width as f64 / self.scale).round().max(1.0) as u32,
+			(self.height as f64 / self.scale).round().max(1.0) as u32,
+		)
+	}
+
+	pub fn get_scale(&self) -> f64 {
+		self.scale
+	}
+
+	pub fn set_scale(&mut self, scale: f64) -> anyhow::Result<()> {
+		if !scale.is_finite() || scale <= 0.0 {
+			anyhow::bail!("Invalid display scale {}", scale);
+		}
+
+		if scale == self.scale {
+			return Ok(());
+		}
+
+		log::debug!("Setting display scale to {}", scale);
+		self.scale = scale;
+		self.wl_output.set_scale(scale);
+		self.swapchain.set_scale(self.width, self.height, scale);
+
+		self.pointer_grab = None;
+		self.reposition_windows();
+		self.configure_bounds();
+
+		Ok(())
+	}
+
+	// Output of the most recently presented buffer
+	pub fn get_output(&self) -> anyhow::Result<DisplayOutput> {
+		self
+			.swapchain
+			.get_output(self.swapchain.get_current_index())
+	}
+
+	// Output of every swapchain buffer, indexed by DisplayFrame::buffer_index
+	pub fn get_all_outputs(&self) -> anyhow::Result<Vec<DisplayOutput>> {
+		self.swapchain.get_all_outputs()
+	}
+
+	// Releases renderer resources, call before dropping the display
+	pub fn destroy(&mut self, renderer: &mut render::WayVRRenderer) {
+		renderer.destroy_swapchain(&mut self.swapchain);
+	}
+
+	pub fn release_buffer(&mut self, buffer_index: usize) {
+		self.swapchain.release(buffer_index);
+	}
+
+	// Reallocates swapchain buffers and lays out windows again.
+	// Previously exported outputs become invalid.
+	pub fn resize(
+		&mut self,
+		renderer: &mut render::WayVRRenderer,
+		width: u32,
+		height: u32,
+	) -> anyhow::Result<()> {
+		if width == self.width && height == self.height {
+			return Ok(());
+		}
+
+		log::debug!("Resizing display to {}x{}", width, height);
+
+		// Make sure the renderer is done with old buffers before they get released
+		renderer.finish()?;
+
+		let output_mode = if self.swapchain.is_shm() {
+			OutputMode::Shm
+		} else {
+			OutputMode::Dmabuf
+		};
+
+		let swapchain =
+			renderer.create_swapchain(width, height, self.swapchain.buffer_count(), output_mode)?;
+		let mut old_swapchain = std::mem::replace(&mut self.swapchain, swapchain);
+		renderer.destroy_swapchain(&mut old_swapchain);
+		self.width = width;
+		self.height = height;
+		self.swapchain.set_scale(width, height, self.scale);
+		self.wl_output.set_size(width, height);
+
+		self.pointer_grab = None;
+		self.reposition_windows();
+		self.configure_bounds();
+
+		Ok(())
+	}
+
+	// Tells every toplevel how much space is available on this display
+	fn configure_bounds(&self) {
+		let (width, height) = self.get_logical_size();
+		for win in &self.displayed_windows {
+			win.toplevel.with_pending_state(|state| {
+				state.bounds = Some((width as i32, height as i32).into());
+			});
+			win.toplevel.send_configure();
+		}
+	}
+
+	// Returns PIDs and exit statuses of processes which have exited since the last call
+	pub fn tick_processes(&mut self) -> Vec<(u32, std::process::ExitStatus)> {
+		let mut exited = Vec::new();
+
+		self
+			.processes
+			.retain_mut(|process| match process.child.try_wait() {
+				Ok(Some(status)) => {
+					exited.push((process.child.id(), status));
+					false
+				}
+				Ok(None) => true,
+				Err(e) => {
+					log::error!("Failed to query process status: {}", e);
+					true
+				}
+			});
+
+		exited
+	}
+
 	pub fn auth_key_matches(&self, auth_key: &str) -> bool {
 		for process in &self.processes {
 			if process.auth_key.as_str() == auth_key {
@@ -116,118 +364,938 @@ impl Display {
 
 	pub fn add_window(&mut self, window_handle: window::WindowHandle, toplevel: &ToplevelSurface) {
 		log::debug!("Attaching toplevel surface into display");
-		self.displayed_windows.push(DisplayWindow {
+		let win = DisplayWindow {
 			handle: window_handle,
 			toplevel: toplevel.clone(),
+			visible: true,
+			restore_rect: None,
+			decoration: None,
+			title_bar: None,
+		};
+
+		// A maximized tile would cover the new window
+		self.restore_maximized_tiles(None);
+
+		// New windows open on top of the stacking order, tiling layouts append them
+		if self.is_floating() {
+			self.displayed_windows.insert(0, win);
+		} else {
+			self.displayed_windows.push(win);
+		}
+
+		if !self.visible {
+			Self::configure_suspended(toplevel, true);
+		}
+
+		self.reposition_windows();
+	}
+
+	pub fn is_visible(&self) -> bool {
+		self.visible
+	}
+
+	// Hidden displays don't render and don't send frame callbacks,
+	// their windows are told they are suspended
+	pub fn set_visible(&mut self, visible: bool) {
+		if self.visible == visible {
+			return;
+		}
+
+		log::debug!("Setting display visibility to {}", visible);
+		self.visible = visible;
+
+		for win in &self.displayed_windows {
+			Self::configure_suspended(&win.toplevel, !visible);
+		}
+	}
+
+	fn configure_suspended(toplevel: &ToplevelSurface, suspended: bool) {
+		toplevel.with_pending_state(|state| {
+			if suspended {
+				state.states.set(xdg_toplevel::State::Suspended);
+				state.states.unset(xdg_toplevel::State::Activated);
+			} else {
+				state.states.unset(xdg_toplevel::State::Suspended);
+				state.states.set(xdg_toplevel::State::Activated);
+			}
 		});
+		toplevel.send_configure();
+	}
+
+	// Returns true if the window was displayed here
+	pub fn remove_window(&mut self, window_handle: window::WindowHandle) -> bool {
+		let Some(idx) = self
+			.displayed_windows
+			.iter()
+			.position(|win| win.handle == window_handle)
+		else {
+			return false;
+		};
+
+		self.displayed_windows.remove(idx);
+		self.layout.remove(idx);
+
+		if let Some(PointerGrab::Move { handle, .. } | PointerGrab::Resize { handle, .. }) =
+			&self.pointer_grab
+		{
+			if *handle == window_handle {
+				self.pointer_grab = None;
+			}
+		}
+
+		log::debug!("Detaching toplevel surface from display");
 		self.reposition_windows();
+		true
 	}
 
-	fn reposition_windows(&mut self) {
-		let window_count = self.displayed_windows.len();
+	// Moves keyboard focus to a surviving window if the focused surface is gone or no longer managed
+	pub fn restore_focus(&self, manager: &mut WayVRManager) {
+		let focus_valid = manager
+			.seat_keyboard
+			.current_focus()
+			.is_some_and(|focus| focus.alive() && self.wm.borrow().find_surface_owner(&focus).is_some());
+
+		if focus_valid {
+			return;
+		}
+
+		let surf = self
+			.displayed_windows
+			.iter()
+			.find(|win| win.toplevel.alive())
+			.map(|win| win.toplevel.wl_surface().clone());
+
+		manager.seat_keyboard.set_focus(
+			&mut manager.state,
+			surf,
+			manager.serial_counter.next_serial(),
+		);
+	}
+
+	// Height of the title bar drawn above the window, 0 if there's none
+	fn title_bar_height(&self, win: &DisplayWindow) -> u32 {
+		if self.decoration_mode == DecorationMode::ServerSide && win.decoration.is_some() {
+			decoration::TITLE_BAR_HEIGHT
+		} else {
+			0
+		}
+	}
+
+	// Answers decoration negotiation of a window displayed here
+	pub fn set_window_decoration(
+		&mut self,
+		window_handle: window::WindowHandle,
+		decoration: decoration::ClientDecoration,
+	) {
+		let Some(win) = self
+			.displayed_windows
+			.iter_mut()
+			.find(|win| win.handle == window_handle)
+		else {
+			return;
+		};
+
+		decoration.send_mode(self.decoration_mode);
+		win.decoration = Some(decoration);
+		self.reposition_windows();
+	}
+
+	pub fn get_decoration_mode(&self) -> DecorationMode {
+		self.decoration_mode
+	}
+
+	pub fn set_decoration_mode(&mut self, mode: DecorationMode) {
+		if self.decoration_mode == mode {
+			return;
+		}
+
+		log::debug!("Setting display decoration mode to {:?}", mode);
+		self.decoration_mode = mode;
+		self.hovered_button = None;
+
+		for win in &mut self.displayed_windows {
+			if let Some(decoration) = &win.decoration {
+				decoration.send_mode(mode);
+			}
+			if mode != DecorationMode::ServerSide {
+				win.title_bar = None;
+			}
+		}
+
+		self.reposition_windows();
+	}
+
+	fn toggle_maximized(&mut self, window_handle: window::WindowHandle) {
+		let rect = self.get_window_rect(window_handle);
+		let Some(win) = self
+			.displayed_windows
+			.iter_mut()
+			.find(|win| win.handle == window_handle)
+		else {
+			return;
+		};
+
+		let restore_rect = win.restore_rect.take();
+		let maximized = restore_rect.is_none();
+		if maximized {
+			win.restore_rect = rect;
+		}
+
+		// Floating windows go back where they were
+		if let (Some(rect), true) = (restore_rect, self.layout.is_floating()) {
+			if let Some(window) = self.wm.borrow_mut().windows.get_mut(&window_handle) {
+				window.set_pos(rect.x, rect.y);
+				window.set_size(rect.w, rect.h);
+			}
+		}
+
+		win.toplevel.with_pending_state(|state| {
+			if maximized {
+				state.states.set(xdg_toplevel::State::Maximized);
+			} else {
+				state.states.unset(xdg_toplevel::State::Maximized);
+			}
+		});
+		win.toplevel.send_configure();
+
+		if maximized && self.is_floating() {
+			self.raise_window(window_handle);
+		}
+		if maximized {
+			self.restore_maximized_tiles(Some(window_handle));
+		}
+		self.reposition_windows();
+	}
+
+	// Tiling layouts show a single maximized window, it hides all the other tiles
+	fn restore_maximized_tiles(&mut self, except: Option<window::WindowHandle>) {
+		if self.is_floating() {
+			return;
+		}
+
+		let maximized: Vec<window::WindowHandle> = self
+			.displayed_windows
+			.iter()
+			.filter(|win| win.restore_rect.is_some() && Some(win.handle) != except)
+			.map(|win| win.handle)
+			.collect();
+
+		for window_handle in maximized {
+			self.toggle_maximized(window_handle);
+		}
+	}
+
+	pub fn has_window(&self, window_handle: window::WindowHandle) -> bool {
+		self
+			.displayed_windows
+			.iter()
+			.any(|win| win.handle == window_handle)
+	}
+
+	// Returns popup geometry relative to the parent's window geometry, constrained to the display bounds.
+	// parent_x/parent_y locate the parent's window geometry relative to the toplevel surface.
+	pub fn constrain_popup(
+		&self,
+		window: &window::Window,
+		parent_x: i32,
+		parent_y: i32,
+		positioner: &PositionerState,
+	) -> Rectangle<i32, Logical> {
+		let (width, height) = self.get_logical_size();
+		let target = Rectangle::from_loc_and_size(
+			(-(window.pos_x + parent_x), -(window.pos_y + parent_y)),
+			(width as i32, height as i32),
+		);
+		positioner.get_unconstrained_geometry(target)
+	}
+
+	pub fn set_layout(&mut self, layout: Box<dyn layout::Layout>) {
+		self.layout = layout;
+		self.reposition_windows();
+	}
+
+	// Brings the window forward in layouts which don't show every window at once
+	pub fn activate_window(&mut self, window_handle: window::WindowHandle) {
+		if let Some(idx) = self
+			.displayed_windows
+			.iter()
+			.position(|win| win.handle == window_handle)
+		{
+			self.layout.activate(idx);
+			self.reposition_windows();
+		}
+	}
+
+	pub fn is_floating(&self) -> bool {
+		self.layout.is_floating()
+	}
+
+	// Moves the window on top of the stacking order
+	pub fn raise_window(&mut self, window_handle: window::WindowHandle) {
+		if let Some(idx) = self
+			.displayed_windows
+			.iter()
+			.position(|win| win.handle == window_handle)
+		{
+			let win = self.displayed_windows.remove(idx);
+			self.displayed_windows.insert(0, win);
+		}
+	}
+
+	fn get_window_rect(&self, window_handle: window::WindowHandle) -> Option<layout::LayoutRect> {
+		let wm = self.wm.borrow();
+		let window = wm.windows.get(&window_handle)?;
+		Some(layout::LayoutRect {
+			x: window.pos_x,
+			y: window.pos_y,
+			w: window.size_x,
+			h: window.size_y,
+		})
+	}
+
+	pub fn start_move(&mut self, manager: &WayVRManager, window_handle: window::WindowHandle) {
+		if !self.is_floating() {
+			return;
+		}
+
+		if let Some(start_rect) = self.get_window_rect(window_handle) {
+			self.pointer_grab = Some(PointerGrab::Move {
+				handle: window_handle,
+				start_cursor: manager.seat_pointer.current_location(),
+				start_rect,
+			});
+		}
+	}
+
+	pub fn start_resize(
+		&mut self,
+		manager: &WayVRManager,
+		window_handle: window::WindowHandle,
+		edges: xdg_toplevel::ResizeEdge,
+	) {
+		if !self.is_floating() {
+			return;
+		}
+
+		if let Some(start_rect) = self.get_window_rect(window_handle) {
+			if let Some(window) = self.wm.borrow().windows.get(&window_handle) {
+				window.toplevel.with_pending_state(|state| {
+					state.states.set(xdg_toplevel::State::Resizing);
+				});
+			}
+
+			self.pointer_grab = Some(PointerGrab::Resize {
+				handle: window_handle,
+				edges,
+				start_cursor: manager.seat_pointer.current_location(),
+				start_rect,
+			});
+		}
+	}
+
+	// Cursor position in logical coordinates
+	fn update_pointer_grab(&self, grab: &PointerGrab, x: f64, y: f64) {
+		let mut wm = self.wm.borrow_mut();
+
+		match grab {
+			PointerGrab::Move {
+				handle,
+				start_cursor,
+				start_rect,
+			} => {
+				if let Some(window) = wm.windows.get_mut(handle) {
+					window.set_pos(
+						start_rect.x + (x - start_cursor.x) as i32,
+						start_rect.y + (y - start_cursor.y) as i32,
+					);
+				}
+			}
+			PointerGrab::Resize {
+				handle,
+				edges,
+				start_cursor,
+				start_rect,
+			} => {
+				let Some(window) = wm.windows.get_mut(handle) else {
+					return;
+				};
 
-		for (i, win) in self.displayed_windows.iter_mut().enumerate() {
-			if let Some(window) = self.wm.borrow_mut().windows.get_mut(&win.handle) {
-				let d_cur = i as f32 / window_count as f32;
-				let d_next = (i + 1) as f32 / window_count as f32;
+				use xdg_toplevel::ResizeEdge;
+				let dx = (x - start_cursor.x) as i32;
+				let dy = (y - start_cursor.y) as i32;
 
-				let left = (d_cur * self.width as f32) as i32;
-				let right = (d_next * self.width as f32) as i32;
+				let left = matches!(
+					edges,
+					ResizeEdge::Left | ResizeEdge::TopLeft | ResizeEdge::BottomLeft
+				);
+				let right = matches!(
+					edges,
+					ResizeEdge::Right | ResizeEdge::TopRight | ResizeEdge::BottomRight
+				);
+				let top = matches!(
+					edges,
+					ResizeEdge::Top | ResizeEdge::TopLeft | ResizeEdge::TopRight
+				);
+				let bottom = matches!(
+					edges,
+					ResizeEdge::Bottom | ResizeEdge::BottomLeft | ResizeEdge::BottomRight
+				);
+
+				let mut w = start_rect.w as i32;
+				let mut h = start_rect.h as i32;
+				if left {
+					w -= dx;
+				} else if right {
+					w += dx;
+				}
+				if top {
+					h -= dy;
+				} else if bottom {
+					h += dy;
+				}
+
+				let (min_size, max_size) = window.get_size_constraints();
+				let layout_window = layout::LayoutWindow {
+					handle: *handle,
+					rect: *start_rect,
+					min_size,
+					max_size,
+				};
+				let (w, h) = layout_window.clamp_size(w.max(1) as u32, h.max(1) as u32);
+
+				// Keep the opposite edge in place
+				let pos_x = if left {
+					start_rect.x + start_rect.w as i32 - w as i32
+				} else {
+					start_rect.x
+				};
+				let pos_y = if top {
+					start_rect.y + start_rect.h as i32 - h as i32
+				} else {
+					start_rect.y
+				};
 
-				window.set_pos(left, 0);
-				window.set_size((right - left) as u32, self.height);
+				window.set_pos(pos_x, pos_y);
+				if window.size_x != w || window.size_y != h {
+					window.set_size(w, h);
+				}
 			}
 		}
 	}
 
-	pub fn tick_render(&self, renderer: &mut GlesRenderer, time_ms: u64) -> anyhow::Result<()> {
-		renderer.bind(self.gles_texture.clone())?;
+	fn end_pointer_grab(&mut self) {
+		let Some(grab) = self.pointer_grab.take() else {
+			return;
+		};
 
-		let size = Size::from((self.width as i32, self.height as i32));
-		let damage: Rectangle<i32, smithay::utils::Physical> =
-			Rectangle::from_loc_and_size((0, 0), size);
+		if let PointerGrab::Resize { handle, .. } = grab {
+			if let Some(window) = self.wm.borrow().windows.get(&handle) {
+				window.toplevel.with_pending_state(|state| {
+					state.states.unset(xdg_toplevel::State::Resizing);
+				});
+				window.toplevel.send_configure();
+			}
+		}
+	}
 
-		let elements: Vec<WaylandSurfaceRenderElement<GlesRenderer>> = self
+	// Layouts arrange whole frames, title bars included
+	fn reposition_windows(&mut self) {
+		let mut wm = self.wm.borrow_mut();
+
+		let mut bars = Vec::new();
+		let layout_windows: Vec<layout::LayoutWindow> = self
 			.displayed_windows
 			.iter()
-			.flat_map(|display_window| {
-				let wm = self.wm.borrow_mut();
-				if let Some(window) = wm.windows.get(&display_window.handle) {
+			.filter_map(|win| {
+				let window = wm.windows.get(&win.handle)?;
+				let bar = self.title_bar_height(win);
+				bars.push(bar);
+
+				// 0 stays unconstrained (or never laid out)
+				let add_bar = |h: u32| if h > 0 { h + bar } else { 0 };
+				let (min_size, max_size) = window.get_size_constraints();
+				Some(layout::LayoutWindow {
+					handle: win.handle,
+					rect: layout::LayoutRect {
+						x: window.pos_x,
+						y: window.pos_y - bar as i32,
+						w: window.size_x,
+						h: add_bar(window.size_y),
+					},
+					min_size: (min_size.0, add_bar(min_size.1)),
+					max_size: (max_size.0, add_bar(max_size.1)),
+				})
+			})
+			.collect();
+
+		let (width, height) = self.get_logical_size();
+		let rects = self.layout.arrange(&layout_windows, width, height);
+
+		// Tiles aren't stacked, a maximized one would be drawn below or above its neighbours
+		let maximized_tile = if self.layout.is_floating() {
+			None
+		} else {
+			layout_windows
+				.iter()
+				.zip(&rects)
+				.filter(|(_, rect)| rect.is_some())
+				.map(|(layout_window, _)| layout_window.handle)
+				.find(|handle| {
+					self
+						.displayed_windows
+						.iter()
+						.any(|win| win.handle == *handle && win.restore_rect.is_some())
+				})
+		};
+
+		for ((layout_window, bar), rect) in layout_windows.iter().zip(bars).zip(rects) {
+			let Some(win) = self
+				.displayed_windows
+				.iter_mut()
+				.find(|win| win.handle == layout_window.handle)
+			else {
+				continue;
+			};
+
+			win.visible = rect.is_some() && maximized_tile.map_or(true, |handle| handle == win.handle);
+
+			let rect = match (rect, win.restore_rect) {
+				(Some(_), Some(_)) => Some(layout_window.fit(layout::LayoutRect {
+					x: 0,
+					y: 0,
+					w: width,
+					h: height,
+				})),
+				(rect, _) => rect,
+			};
+
+			if let (Some(rect), Some(window)) = (rect, wm.windows.get_mut(&win.handle)) {
+				let h = rect.h.saturating_sub(bar).max(1);
+				window.set_pos(rect.x, rect.y + bar as i32);
+				if window.size_x != rect.w || window.size_y != h {
+					window.set_size(rect.w, h);
+				}
+			}
+		}
+	}
+
+	// Redraws title bars whose title, width or hovered button changed
+	fn update_title_bars(&mut self) {
+		let wm = self.wm.borrow();
+
+		for win in &mut self.displayed_windows {
+			let decorated =
+				self.decoration_mode == DecorationMode::ServerSide && win.decoration.is_some();
+			let Some(window) = wm.windows.get(&win.handle).filter(|_| decorated) else {
+				win.title_bar = None;
+				continue;
+			};
+
+			let hovered = self
+				.hovered_button
+				.filter(|(handle, _)| *handle == win.handle)
+				.map(|(_, button)| button);
+
+			win
+				.title_bar
+				.get_or_insert_with(decoration::TitleBar::new)
+				.update(
+					&window.get_title().unwrap_or_default(),
+					window.size_x,
+					self.scale,
+					hovered,
+				);
+		}
+	}
+
+	// Topmost first, popups of a window go right above it
+	fn collect_render_elements<R>(&self, renderer: &mut R) -> Vec<DisplayRenderElement<R>>
+	where
+		R: Renderer + ImportAll + ImportMem,
+		R::TextureId: Send + Clone + 'static,
+	{
+		let wm = self.wm.borrow();
+		let mut elements: Vec<DisplayRenderElement<R>> = Vec::new();
+
+		// Positions are logical, elements need physical ones
+		let scale = self.scale;
+		let to_physical = |x: i32, y: i32| -> Point<i32, Physical> {
+			Point::<i32, Logical>::from((x, y)).to_physical_precise_round(scale)
+		};
+
+		for display_window in self.displayed_windows.iter().filter(|win| win.visible) {
+			if let Some(window) = wm.windows.get(&display_window.handle) {
+				for popup in window.popups.iter().rev() {
+					let (pos_x, pos_y) = popup.get_surface_pos();
+					elements.extend(
+						render_elements_from_surface_tree(
+							renderer,
+							popup.surface.wl_surface(),
+							to_physical(window.pos_x + pos_x, window.pos_y + pos_y),
+							scale,
+							1.0,
+							Kind::Unspecified,
+						)
+						.into_iter()
+						.map(DisplayRenderElement::Surface),
+					);
+				}
+
+				elements.extend(
 					render_elements_from_surface_tree(
 						renderer,
 						display_window.toplevel.wl_surface(),
-						(window.pos_x, window.pos_y),
-						1.0,
+						to_physical(window.pos_x, window.pos_y),
+						scale,
 						1.0,
 						Kind::Unspecified,
 					)
-				} else {
-					// Failed to fetch window
-					vec![]
+					.into_iter()
+					.map(DisplayRenderElement::Surface),
+				);
+
+				if let Some(title_bar) = &display_window.title_bar {
+					let bar = decoration::TITLE_BAR_HEIGHT as i32;
+					elements.extend(
+						title_bar
+							.render_element(renderer, to_physical(window.pos_x, window.pos_y - bar))
+							.map(DisplayRenderElement::TitleBar),
+					);
 				}
-			})
-			.collect();
+			}
+		}
 
-		let mut frame = renderer.render(size, Transform::Normal)?;
+		elements
+	}
 
+	// Renders into the bound buffer, returns false if nothing was damaged
+	fn render_elements<R>(
+		&mut self,
+		renderer: &mut R,
+		age: usize,
+		elements: &[DisplayRenderElement<R>],
+	) -> anyhow::Result<(bool, SyncPoint)>
+	where
+		R: Renderer + ImportAll + ImportMem,
+		R::TextureId: Send + Clone + 'static,
+	{
 		let clear_opacity = if self.displayed_windows.is_empty() {
 			0.5
 		} else {
 			0.0
 		};
 
-		frame.clear(Color32F::new(1.0, 1.0, 1.0, clear_opacity), &[damage])?;
+		// The damage tracker doesn't know about the clear color, redraw everything if it changes
+		let age = if self.clear_opacity != clear_opacity {
+			self.clear_opacity = clear_opacity;
+			self.swapchain.reset_age();
+			0
+		} else {
+			age
+		};
+
+		let result = self
+			.swapchain
+			.damage_tracker
+			.render_output(
+				renderer,
+				age,
+				elements,
+				Color32F::new(1.0, 1.0, 1.0, clear_opacity),
+			)
+			.map_err(|e| anyhow::anyhow!("Failed to render display: {:?}", e))?;
+
+		Ok((result.damage.is_some(), result.sync))
+	}
+
+	fn render_gles(&mut self, renderer: &mut GlesRenderer) -> anyhow::Result<Option<DisplayFrame>> {
+		// CPU readback finishes asynchronously, the frame gets presented a tick later
+		let read_back = if self.swapchain.is_shm() {
+			self.swapchain.poll_readbacks(renderer)?
+		} else {
+			None
+		};
+
+		let read_back_frame = read_back.map(|buffer_index| DisplayFrame {
+			buffer_index,
+			sync_fd: None,
+		});
 
-		draw_render_elements(&mut frame, 1.0, &elements, &[damage])?;
+		let Some((buffer_index, age)) = self.swapchain.acquire() else {
+			// Every buffer is busy, try again later
+			return Ok(read_back_frame);
+		};
+
+		self.swapchain.bind(renderer, buffer_index)?;
 
-		let _sync_point = frame.finish()?;
+		let elements = self.collect_render_elements(renderer);
+		let (rendered, sync) = self.render_elements(renderer, age, &elements)?;
 
-		for window in &self.displayed_windows {
-			send_frames_surface_tree(window.toplevel.wl_surface(), time_ms as u32);
+		if !rendered {
+			return Ok(read_back_frame);
 		}
 
-		Ok(())
+		self.swapchain.mark_rendered(buffer_index);
+
+		if self.swapchain.is_shm() {
+			self.swapchain.start_readback(renderer, buffer_index)?;
+			return Ok(read_back_frame);
+		}
+
+		self.swapchain.present(buffer_index);
+
+		let sync_fd = sync.export();
+		if sync_fd.is_none() {
+			// No way to hand the fence over, block until the frame is done
+			let _ = sync.wait();
+		}
+
+		Ok(Some(DisplayFrame {
+			buffer_index,
+			sync_fd,
+		}))
+	}
+
+	#[cfg(feature = "pixman")]
+	fn render_pixman(
+		&mut self,
+		renderer: &mut PixmanRenderer,
+	) -> anyhow::Result<Option<DisplayFrame>> {
+		let Some((buffer_index, age)) = self.swapchain.acquire() else {
+			return Ok(None);
+		};
+
+		// Unbound right away, the target points into swapchain memory
+		unsafe { self.swapchain.bind_pixman(renderer, buffer_index)? };
+		let elements = self.collect_render_elements(renderer);
+		let res = self.render_elements(renderer, age, &elements);
+		let _ = renderer.unbind();
+		let (rendered, _) = res?;
+
+		if !rendered {
+			return Ok(None);
+		}
+
+		// Pixman renders synchronously, the buffer is ready already
+		self.swapchain.mark_rendered(buffer_index);
+		self.swapchain.present(buffer_index);
+
+		Ok(Some(DisplayFrame {
+			buffer_index,
+			sync_fd: None,
+		}))
+	}
+
+	// Reads back the most recently presented buffer
+	pub fn capture(
+		&mut self,
+		renderer: &mut render::WayVRRenderer,
+	) -> anyhow::Result<capture::RgbaImage> {
+		let buffer_index = self.swapchain.get_current_index();
+
+		match renderer {
+			render::WayVRRenderer::Gles(ctx) => {
+				self.swapchain.bind(&mut ctx.renderer, buffer_index)?;
+				capture::read_framebuffer(&mut ctx.renderer, self.width, self.height)
+			}
+			#[cfg(feature = "pixman")]
+			render::WayVRRenderer::Pixman(renderer) => {
+				unsafe { self.swapchain.bind_pixman(renderer, buffer_index)? };
+				let res = capture::read_framebuffer(renderer, self.width, self.height);
+				let _ = renderer.unbind();
+				res
+			}
+		}
+	}
+
+	pub fn has_wl_output(&self, output: &smithay::output::Output) -> bool {
+		self.wl_output.output == *output
+	}
+
+	// Copies the most recently presented frame into a screencopy client buffer
+	pub fn copy_to_buffer(
+		&mut self,
+		renderer: &mut render::WayVRRenderer,
+		buffer: &WlBuffer,
+		region: &Rectangle<i32, Physical>,
+	) -> anyhow::Result<()> {
+		if let Ok(dmabuf) = get_dmabuf(buffer) {
+			let render::WayVRRenderer::Gles(ctx) = renderer else {
+				anyhow::bail!("dma-buf copies need the GLES renderer");
+			};
+
+			let dmabuf = dmabuf.clone();
+			let dst = Rectangle::from_loc_and_size((0, 0), region.size);
+
+			self
+				.swapchain
+				.bind(&mut ctx.renderer, self.swapchain.get_current_index())?;
+			ctx
+				.renderer
+				.blit_to(dmabuf, *region, dst, TextureFilter::Nearest)
+				.map_err(|e| anyhow::anyhow!("Failed to blit into dma-buf: {:?}", e))?;
+
+			// The client reads the buffer as soon as the frame is ready
+			return renderer.finish();
+		}
+
+		let image = self.capture(renderer)?;
+		screencopy::write_shm_buffer(buffer, &image, region)
+	}
+
+	// Returns None if nothing changed since the last frame
+	pub fn tick_render(
+		&mut self,
+		renderer: &mut render::WayVRRenderer,
+		time_ms: u64,
+	) -> anyhow::Result<Option<DisplayFrame>> {
+		self.update_title_bars();
+
+		let frame = match renderer {
+			render::WayVRRenderer::Gles(ctx) => self.render_gles(&mut ctx.renderer)?,
+			#[cfg(feature = "pixman")]
+			render::WayVRRenderer::Pixman(renderer) => self.render_pixman(renderer)?,
+		};
+
+		let wm = self.wm.borrow();
+		let output = &self.wl_output.output;
+		for display_window in &self.displayed_windows {
+			let surface = display_window.toplevel.wl_surface();
+			output::enter_surface_tree(surface, output);
+			send_frames_surface_tree(surface, time_ms as u32);
+
+			if let Some(window) = wm.windows.get(&display_window.handle) {
+				for popup in &window.popups {
+					let surface = popup.surface.wl_surface();
+					output::enter_surface_tree(surface, output);
+					send_frames_surface_tree(surface, time_ms as u32);
+				}
+			}
+		}
+
+		Ok(frame)
 	}
 
-	fn get_hovered_window(&self, cursor_x: u32, cursor_y: u32) -> Option<window::WindowHandle> {
+	// Cursor position in logical coordinates
+	fn get_hovered_window(&self, cursor_x: f64, cursor_y: f64) -> Option<Hovered> {
 		let wm = self.wm.borrow();
+		let cursor_x = cursor_x.floor() as i32;
+		let cursor_y = cursor_y.floor() as i32;
 
-		for cell in self.displayed_windows.iter() {
+		// Same order as rendering, popups of a window are above it
+		for cell in self.displayed_windows.iter().filter(|win| win.visible) {
 			if let Some(window) = wm.windows.get(&cell.handle) {
-				if (cursor_x as i32) >= window.pos_x
-					&& (cursor_x as i32) < window.pos_x + window.size_x as i32
-					&& (cursor_y as i32) >= window.pos_y
-					&& (cursor_y as i32) < window.pos_y + window.size_y as i32
-				{
-					return Some(cell.handle);
+				for popup in window.popups.iter().rev() {
+					if popup.contains(cursor_x - window.pos_x, cursor_y - window.pos_y) {
+						let (pos_x, pos_y) = popup.get_surface_pos();
+						return Some(Hovered::Surface(HoveredSurface {
+							handle: cell.handle,
+							surface: popup.surface.wl_surface().clone(),
+							pos_x: window.pos_x + pos_x,
+							pos_y: window.pos_y + pos_y,
+						}));
+					}
+				}
+
+				if window.contains(cursor_x, cursor_y) {
+					return Some(Hovered::Surface(HoveredSurface {
+						handle: cell.handle,
+						surface: window.toplevel.wl_surface().clone(),
+						pos_x: window.pos_x,
+						pos_y: window.pos_y,
+					}));
+				}
+
+				let bar = self.title_bar_height(cell) as i32;
+				if bar > 0 {
+					if let Some(hit) = decoration::hit_test(
+						window.size_x,
+						cursor_x - window.pos_x,
+						cursor_y - (window.pos_y - bar),
+					) {
+						return Some(Hovered::TitleBar(cell.handle, hit));
+					}
 				}
 			}
 		}
 		None
 	}
 
-	pub fn send_mouse_move(&self, manager: &mut WayVRManager, x: u32, y: u32) {
-		if let Some(window_handle) = self.get_hovered_window(x, y) {
-			let wm = self.wm.borrow();
-			if let Some(window) = wm.windows.get(&window_handle) {
-				let surf = window.toplevel.wl_surface().clone();
-				let point = Point::<f64, Logical>::from((
-					(x as i32 - window.pos_x) as f64,
-					(y as i32 - window.pos_y) as f64,
-				));
-
-				manager.seat_pointer.motion(
-					&mut manager.state,
-					Some((surf, Point::from((0.0, 0.0)))),
-					&input::pointer::MotionEvent {
-						serial: manager.serial_counter.next_serial(),
-						time: 0,
-						location: point,
-					},
-				);
+	// Takes physical (display pixel) coordinates
+	pub fn send_mouse_move(&mut self, manager: &mut WayVRManager, x: u32, y: u32) {
+		let x = x as f64 / self.scale;
+		let y = y as f64 / self.scale;
+
+		if let Some(grab) = &self.pointer_grab {
+			self.update_pointer_grab(grab, x, y);
+			return;
+		}
+
+		let hovered = self.get_hovered_window(x, y);
+
+		self.hovered_button = match &hovered {
+			Some(Hovered::TitleBar(handle, decoration::TitleBarHit::Button(button))) => {
+				Some((*handle, *button))
+			}
+			_ => None,
+		};
+
+		let focus = match hovered {
+			// Pointer location is kept in logical display coordinates, so it can be hit-tested again later
+			Some(Hovered::Surface(hovered)) => Some((
+				hovered.surface,
+				Point::<f64, Logical>::from((hovered.pos_x as f64, hovered.pos_y as f64)),
+			)),
+			// The title bar isn't a client surface, the previous one gets left
+			Some(Hovered::TitleBar(..)) => None,
+			None => return,
+		};
+
+		manager.seat_pointer.motion(
+			&mut manager.state,
+			focus,
+			&input::pointer::MotionEvent {
+				serial: manager.serial_counter.next_serial(),
+				time: 0,
+				location: Point::<f64, Logical>::from((x, y)),
+			},
+		);
+
+		manager.seat_pointer.frame(&mut manager.state);
+	}
+
+	// Clicking outside of a grabbed popup chain dismisses it
+	fn dismiss_popups(&self, manager: &mut WayVRManager, hovered: Option<&HoveredSurface>) {
+		let mut wm = self.wm.borrow_mut();
 
-				manager.seat_pointer.frame(&mut manager.state);
+		for display_window in &self.displayed_windows {
+			let Some(window) = wm.windows.get_mut(&display_window.handle) else {
+				continue;
+			};
+
+			if !window.has_grabbed_popup() {
+				continue;
+			}
+
+			if let Some(hovered) = hovered {
+				if hovered.handle == display_window.handle
+					&& window
+						.popups
+						.iter()
+						.any(|popup| popup.surface.wl_surface() == &hovered.surface)
+				{
+					continue;
+				}
 			}
+
+			window.dismiss_popups();
+
+			// Give keyboard focus back to the toplevel
+			manager.seat_keyboard.set_focus(
+				&mut manager.state,
+				Some(window.toplevel.wl_surface().clone()),
+				manager.serial_counter.next_serial(),
+			);
 		}
 	}
 
@@ -239,15 +1307,31 @@ impl Display {
 		}
 	}
 
-	pub fn send_mouse_down(&self, manager: &mut WayVRManager, index: wayvr::MouseIndex) {
+	pub fn send_mouse_down(&mut self, manager: &mut WayVRManager, index: wayvr::MouseIndex) {
 		// Change keyboard focus to pressed window
 		let loc = manager.seat_pointer.current_location();
 
-		if let Some(window_handle) =
-			self.get_hovered_window(loc.x.max(0.0) as u32, loc.y.max(0.0) as u32)
-		{
+		let hovered = match self.get_hovered_window(loc.x, loc.y) {
+			Some(Hovered::TitleBar(handle, hit)) => {
+				let button = Self::get_mouse_index_number(index);
+				if !self.consumed_buttons.contains(&button) {
+					self.consumed_buttons.push(button);
+				}
+				self.click_title_bar(manager, handle, hit);
+				return;
+			}
+			Some(Hovered::Surface(hovered)) => Some(hovered),
+			None => None,
+		};
+		self.dismiss_popups(manager, hovered.as_ref());
+
+		if let Some(hovered) = hovered {
+			if self.is_floating() {
+				self.raise_window(hovered.handle);
+			}
+
 			let wm = self.wm.borrow();
-			if let Some(window) = wm.windows.get(&window_handle) {
+			if let Some(window) = wm.windows.get(&hovered.handle) {
 				let surf = window.toplevel.wl_surface().clone();
 
 				if manager.seat_keyboard.current_focus().is_none() {
@@ -273,11 +1357,58 @@ impl Display {
 		manager.seat_pointer.frame(&mut manager.state);
 	}
 
-	pub fn send_mouse_up(&self, manager: &mut WayVRManager, index: wayvr::MouseIndex) {
+	// Title bar clicks are handled here, clients never see them
+	fn click_title_bar(
+		&mut self,
+		manager: &mut WayVRManager,
+		window_handle: window::WindowHandle,
+		hit: decoration::TitleBarHit,
+	) {
+		self.dismiss_popups(manager, None);
+
+		if self.is_floating() {
+			self.raise_window(window_handle);
+		}
+
+		match hit {
+			decoration::TitleBarHit::Button(decoration::TitleBarButton::Close) => {
+				if let Some(win) = self
+					.displayed_windows
+					.iter()
+					.find(|win| win.handle == window_handle)
+				{
+					win.toplevel.send_close();
+				}
+			}
+			decoration::TitleBarHit::Button(decoration::TitleBarButton::Maximize) => {
+				self.toggle_maximized(window_handle);
+			}
+			decoration::TitleBarHit::Bar => {
+				let maximized = self
+					.displayed_windows
+					.iter()
+					.any(|win| win.handle == window_handle && win.restore_rect.is_some());
+				if !maximized {
+					self.start_move(manager, window_handle);
+				}
+			}
+		}
+	}
+
+	pub fn send_mouse_up(&mut self, manager: &mut WayVRManager, index: wayvr::MouseIndex) {
+		self.end_pointer_grab();
+
+		// The client never saw the press
+		let button = Self::get_mouse_index_number(index);
+		if let Some(idx) = self.consumed_buttons.iter().position(|b| *b == button) {
+			self.consumed_buttons.remove(idx);
+			return;
+		}
+
 		manager.seat_pointer.button(
 			&mut manager.state,
 			&input::pointer::ButtonEvent {
-				button: Self::get_mouse_index_number(index),
+				button,
 				serial: manager.serial_counter.next_serial(),
 				time: 0,
 				state: smithay::backend::input::ButtonState::Released,
@@ -1,11 +1,30 @@
-use crate::egl_ex;
+use std::{
+	ffi::CStr,
+	fs::File,
+	os::fd::{FromRawFd, OwnedFd},
+	path::{Path, PathBuf},
+};
+
+use crate::{egl_ex, quirks};
 use anyhow::anyhow;
+use smithay::{
+	backend::{allocator::gbm::GbmDevice, renderer::gles::ffi},
+	reexports::gbm::AsRaw,
+};
 
 pub struct EGLData {
 	pub egl: khronos_egl::Instance<khronos_egl::Static>,
 	pub display: khronos_egl::Display,
 	pub config: khronos_egl::Config,
 	pub context: khronos_egl::Context,
+	pub platform: EGLPlatform,
+	pub device: Option<RenderDevice>, // None if the driver doesn't tell
+	pub gl_info: quirks::GLInfo,
+	pub quirks: quirks::ModifierQuirks,
+
+	// Allocates dma-bufs with negotiated formats.
+	// Has to outlive the display on the GBM platform.
+	gbm_device: Option<GbmDevice<File>>,
 }
 
 #[macro_export]
@@ -15,40 +34,572 @@ macro_rules! bind_egl_function {
 	};
 }
 
-#[derive(Clone)]
+#[derive(Clone, Debug)]
 pub struct DMAbufModifierInfo {
 	pub modifiers: Vec<u64>,
 	pub fourcc: u32,
 }
 
-#[derive(Clone)]
-pub struct DMAbufData {
-	pub fd: i32,
+// Format and modifier pair, as supported by the host's dma-buf importer
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct DrmFormat {
+	pub fourcc: u32,
+	pub modifier: u64,
+}
+
+pub const MAX_DMABUF_PLANES: usize = 4;
+pub const DRM_FORMAT_MOD_INVALID: u64 = 0x00ffffffffffffff;
+
+#[derive(Debug)]
+pub struct DMAbufPlane {
+	pub fd: OwnedFd, // Owned by every copy, try_clone duplicates it
 	pub stride: i32,
 	pub offset: i32,
+}
+
+impl DMAbufPlane {
+	// Fails if the process ran out of fds
+	pub fn try_clone(&self) -> anyhow::Result<Self> {
+		Ok(Self {
+			fd: self
+				.fd
+				.try_clone()
+				.map_err(|e| anyhow::anyhow!("Failed to duplicate dma-buf fd: {}", e))?,
+			stride: self.stride,
+			offset: self.offset,
+		})
+	}
+}
+
+#[derive(Debug)]
+pub struct DMAbufData {
+	// Some modifiers (e.g. AMD DCC, Intel CCS) add auxiliary planes, all of them have to be imported
+	pub planes: Vec<DMAbufPlane>,
 	pub mod_info: DMAbufModifierInfo,
+	// Modifier the buffer actually uses, import with this one if set (instead of mod_info)
+	pub modifier: Option<u64>,
+	pub device: Option<RenderDevice>, // GPU the buffer was allocated on
+}
+
+impl DMAbufData {
+	pub fn try_clone(&self) -> anyhow::Result<Self> {
+		Ok(Self {
+			planes: self
+				.planes
+				.iter()
+				.map(|plane| plane.try_clone())
+				.collect::<anyhow::Result<_>>()?,
+			mod_info: self.mod_info.clone(),
+			modifier: self.modifier,
+			device: self.device.clone(),
+		})
+	}
+}
+
+struct OpenedDisplay {
+	display: khronos_egl::Display,
+	device: Option<RenderDevice>,
+	gbm_device: Option<GbmDevice<File>>,
+}
+
+// Where the EGL display comes from
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum EGLPlatform {
+	// First platform that works, in the order listed below
+	Auto,
+	// EGL_EXT_platform_device, GPU picked by its DRM render node
+	Device,
+	// EGL_MESA_platform_surfaceless, also works with llvmpipe
+	Surfaceless,
+	// EGL_KHR_platform_gbm on top of a DRM render node
+	Gbm,
+	// eglGetDisplay(EGL_DEFAULT_DISPLAY), requires a running X11 or Wayland session
+	DefaultDisplay,
+}
+
+const AUTO_PLATFORMS: [EGLPlatform; 4] = [
+	EGLPlatform::Device,
+	EGLPlatform::Surfaceless,
+	EGLPlatform::Gbm,
+	EGLPlatform::DefaultDisplay,
+];
+
+fn load_egl_func(
+	egl: &khronos_egl::Instance<khronos_egl::Static>,
+	func_name: &str,
+) -> anyhow::Result<extern "system" fn()> {
+	let raw_fn = egl.get_proc_address(func_name).ok_or(anyhow::anyhow!(
+		"Required EGL function {} not found",
+		func_name
+	))?;
+	Ok(raw_fn)
+}
+
+fn get_client_extensions(egl: &khronos_egl::Instance<khronos_egl::Static>) -> Vec<String> {
+	// Fails if EGL_EXT_client_extensions is unsupported
+	match egl.query_string(None, khronos_egl::EXTENSIONS) {
+		Ok(extensions) => extensions
+			.to_string_lossy()
+			.split_whitespace()
+			.map(String::from)
+			.collect(),
+		Err(_) => Vec::new(),
+	}
+}
+
+// DRM render nodes (/dev/dri/renderD*), sorted by minor number
+pub fn find_render_nodes() -> Vec<PathBuf> {
+	let Ok(entries) = std::fs::read_dir("/dev/dri") else {
+		return Vec::new();
+	};
+
+	let mut nodes: Vec<PathBuf> = entries
+		.flatten()
+		.map(|entry| entry.path())
+		.filter(|path| {
+			path
+				.file_name()
+				.is_some_and(|name| name.to_string_lossy().starts_with("renderD"))
+		})
+		.collect();
+
+	nodes.sort();
+	nodes
+}
+
+unsafe fn get_platform_display(
+	egl: &khronos_egl::Instance<khronos_egl::Static>,
+	platform: khronos_egl::Enum,
+	native_display: *mut std::ffi::c_void,
+) -> anyhow::Result<khronos_egl::Display> {
+	use egl_ex::PFNEGLGETPLATFORMDISPLAYEXTPROC;
+
+	let egl_get_platform_display_ext = bind_egl_function!(
+		PFNEGLGETPLATFORMDISPLAYEXTPROC,
+		&load_egl_func(egl, "eglGetPlatformDisplayEXT")?
+	);
+
+	let attrib_list = [khronos_egl::NONE];
+	let display = egl_get_platform_display_ext(platform, native_display, attrib_list.as_ptr());
+	if display.is_null() {
+		anyhow::bail!(
+			"eglGetPlatformDisplayEXT failed for platform {:#x}",
+			platform
+		);
+	}
+
+	Ok(khronos_egl::Display::from_ptr(display))
+}
+
+// Preferred GPU, matches both its primary and render node
+#[derive(Clone, Debug)]
+pub enum DeviceSelector {
+	Path(PathBuf),
+	DevT(u64),
+}
+
+// GPU the compositor renders on. Compare the dev_t values against
+// VkPhysicalDeviceDrmPropertiesEXT to import dma-bufs on the same device.
+#[derive(Clone, Debug)]
+pub struct RenderDevice {
+	pub render_node: PathBuf,
+	pub render_dev_t: u64,
+	pub primary_dev_t: Option<u64>,
+	pub driver: String, // Kernel driver name, e.g. "amdgpu"
+}
+
+fn get_dev_t(path: &Path) -> anyhow::Result<u64> {
+	Ok(rustix::fs::stat(path)?.st_rdev)
+}
+
+fn get_sysfs_device_dir(dev_t: u64) -> PathBuf {
+	PathBuf::from(format!(
+		"/sys/dev/char/{}:{}/device",
+		rustix::fs::major(dev_t),
+		rustix::fs::minor(dev_t)
+	))
+}
+
+// Render node belonging to the same GPU as the primary node
+fn get_render_node_from_primary(primary_dev_t: u64) -> Option<PathBuf> {
+	let entries = std::fs::read_dir(get_sysfs_device_dir(primary_dev_t).join("drm")).ok()?;
+	entries
+		.flatten()
+		.map(|entry| entry.file_name().to_string_lossy().to_string())
+		.find(|name| name.starts_with("renderD"))
+		.map(|name| PathBuf::from("/dev/dri").join(name))
+}
+
+impl RenderDevice {
+	fn new(render_node: Option<PathBuf>, primary_node: Option<PathBuf>) -> anyhow::Result<Self> {
+		let primary_dev_t = match &primary_node {
+			Some(path) => Some(get_dev_t(path)?),
+			None => None,
+		};
+
+		let render_node = render_node
+			.or_else(|| primary_dev_t.and_then(get_render_node_from_primary))
+			.ok_or(anyhow!("Device has no DRM render node"))?;
+
+		let render_dev_t = get_dev_t(&render_node)?;
+
+		let driver = std::fs::read_link(get_sysfs_device_dir(render_dev_t).join("driver"))
+			.ok()
+			.and_then(|path| {
+				path
+					.file_name()
+					.map(|name| name.to_string_lossy().to_string())
+			})
+			.unwrap_or_else(|| String::from("unknown"));
+
+		Ok(Self {
+			render_node,
+			render_dev_t,
+			primary_dev_t,
+			driver,
+		})
+	}
+
+	pub fn matches(&self, selector: &DeviceSelector) -> bool {
+		let dev_t = match selector {
+			DeviceSelector::Path(path) => match get_dev_t(path) {
+				Ok(dev_t) => dev_t,
+				Err(_) => return false,
+			},
+			DeviceSelector::DevT(dev_t) => *dev_t,
+		};
+
+		dev_t == self.render_dev_t || Some(dev_t) == self.primary_dev_t
+	}
+}
+
+// Requires a current context
+unsafe fn query_gl_info(
+	egl: &khronos_egl::Instance<khronos_egl::Static>,
+) -> anyhow::Result<quirks::GLInfo> {
+	use egl_ex::PFNGLGETSTRINGPROC;
+
+	let gl_get_string = bind_egl_function!(PFNGLGETSTRINGPROC, &load_egl_func(egl, "glGetString")?);
+
+	let get_string = |name: u32| {
+		let value = gl_get_string(name);
+		if value.is_null() {
+			String::new()
+		} else {
+			CStr::from_ptr(value).to_string_lossy().to_string()
+		}
+	};
+
+	Ok(quirks::GLInfo {
+		renderer: get_string(ffi::RENDERER),
+		vendor: get_string(ffi::VENDOR),
+		version: get_string(ffi::VERSION),
+	})
+}
+
+fn open_gbm_device(render_node: &Path) -> anyhow::Result<GbmDevice<File>> {
+	let file = File::options().read(true).write(true).open(render_node)?;
+	Ok(GbmDevice::new(file)?)
+}
+
+unsafe fn query_device_string(
+	egl: &khronos_egl::Instance<khronos_egl::Static>,
+	device: egl_ex::EGLDeviceEXT,
+	name: khronos_egl::Int,
+) -> anyhow::Result<Option<PathBuf>> {
+	use egl_ex::PFNEGLQUERYDEVICESTRINGEXTPROC;
+
+	let egl_query_device_string_ext = bind_egl_function!(
+		PFNEGLQUERYDEVICESTRINGEXTPROC,
+		&load_egl_func(egl, "eglQueryDeviceStringEXT")?
+	);
+
+	// NULL if the driver doesn't know this string (e.g. software devices)
+	let value = egl_query_device_string_ext(device, name);
+	if value.is_null() {
+		return Ok(None);
+	}
+
+	Ok(Some(PathBuf::from(
+		CStr::from_ptr(value).to_string_lossy().to_string(),
+	)))
+}
+
+unsafe fn get_egl_device_info(
+	egl: &khronos_egl::Instance<khronos_egl::Static>,
+	device: egl_ex::EGLDeviceEXT,
+) -> anyhow::Result<RenderDevice> {
+	let render_node = query_device_string(egl, device, egl_ex::EGL_DRM_RENDER_NODE_FILE_EXT)?;
+	let primary_node = query_device_string(egl, device, egl_ex::EGL_DRM_DEVICE_FILE_EXT)?;
+	RenderDevice::new(render_node, primary_node)
+}
+
+// EGL_EXT_device_query, works on displays of every platform
+unsafe fn query_display_device(
+	egl: &khronos_egl::Instance<khronos_egl::Static>,
+	display: khronos_egl::Display,
+) -> anyhow::Result<RenderDevice> {
+	use egl_ex::PFNEGLQUERYDISPLAYATTRIBEXTPROC;
+
+	let egl_query_display_attrib_ext = bind_egl_function!(
+		PFNEGLQUERYDISPLAYATTRIBEXTPROC,
+		&load_egl_func(egl, "eglQueryDisplayAttribEXT")?
+	);
+
+	let mut device: khronos_egl::Attrib = 0;
+	if egl_query_display_attrib_ext(display.as_ptr(), egl_ex::EGL_DEVICE_EXT, &mut device)
+		!= khronos_egl::TRUE
+	{
+		anyhow::bail!("eglQueryDisplayAttribEXT failed");
+	}
+
+	get_egl_device_info(egl, device as egl_ex::EGLDeviceEXT)
+}
+
+unsafe fn get_device_display(
+	egl: &khronos_egl::Instance<khronos_egl::Static>,
+	preferred_device: Option<&DeviceSelector>,
+) -> anyhow::Result<(khronos_egl::Display, RenderDevice)> {
+	use egl_ex::PFNEGLQUERYDEVICESEXTPROC;
+
+	let egl_query_devices_ext = bind_egl_function!(
+		PFNEGLQUERYDEVICESEXTPROC,
+		&load_egl_func(egl, "eglQueryDevicesEXT")?
+	);
+
+	let mut num_devices: khronos_egl::Int = 0;
+	egl_query_devices_ext(0, std::ptr::null_mut(), &mut num_devices);
+
+	let mut devices: Vec<egl_ex::EGLDeviceEXT> = vec![std::ptr::null_mut(); num_devices as usize];
+	egl_query_devices_ext(num_devices, devices.as_mut_ptr(), &mut num_devices);
+	devices.truncate(num_devices as usize);
+
+	for device in devices {
+		// Software devices don't have DRM nodes
+		let Ok(info) = get_egl_device_info(egl, device) else {
+			continue;
+		};
+
+		log::debug!(
+			"EGL device: {} ({}, dev_t {:#x})",
+			info.render_node.display(),
+			info.driver,
+			info.render_dev_t
+		);
+
+		if preferred_device.is_some_and(|selector| !info.matches(selector)) {
+			continue;
+		}
+
+		// Try the next device if this one can't be used, other platforms are only tried after all of them
+		let display = match get_platform_display(egl, egl_ex::EGL_PLATFORM_DEVICE_EXT, device) {
+			Ok(display) => display,
+			Err(e) => {
+				log::warn!(
+					"Failed to get EGL display of {}: {}",
+					info.render_node.display(),
+					e
+				);
+				continue;
+			}
+		};
+
+		// Initializing again later is a no-op
+		if let Err(e) = egl.initialize(display) {
+			log::warn!(
+				"eglInitialize failed on {}: {}",
+				info.render_node.display(),
+				e
+			);
+			continue;
+		}
+
+		return Ok((display, info));
+	}
+
+	anyhow::bail!("No usable EGL device with a DRM render node found");
 }
 
 impl EGLData {
 	pub fn load_func(&self, func_name: &str) -> anyhow::Result<extern "system" fn()> {
-		let raw_fn = self.egl.get_proc_address(func_name).ok_or(anyhow::anyhow!(
-			"Required EGL function {} not found",
-			func_name
-		))?;
-		Ok(raw_fn)
+		load_egl_func(&self.egl, func_name)
 	}
 
-	pub fn new() -> anyhow::Result<EGLData> {
+	// Returns an uninitialized display of the given platform (not Auto)
+	// and the device it is going to use, if already known
+	unsafe fn open_display(
+		egl: &khronos_egl::Instance<khronos_egl::Static>,
+		platform: EGLPlatform,
+		client_extensions: &[String],
+		preferred_device: Option<&DeviceSelector>,
+	) -> anyhow::Result<OpenedDisplay> {
+		let has_extension = |name: &str| client_extensions.iter().any(|ext| ext == name);
+
+		let require_extension = |name: &str| {
+			if has_extension(name) {
+				Ok(())
+			} else {
+				Err(anyhow!("{} is not supported", name))
+			}
+		};
+
+		if platform != EGLPlatform::DefaultDisplay {
+			require_extension("EGL_EXT_platform_base")?;
+		}
+
+		match platform {
+			EGLPlatform::Auto => unreachable!(),
+			EGLPlatform::Device => {
+				require_extension("EGL_EXT_platform_device")?;
+				require_extension("EGL_EXT_device_enumeration")?;
+				let (display, device) = get_device_display(egl, preferred_device)?;
+				Ok(OpenedDisplay {
+					display,
+					device: Some(device),
+					gbm_device: None,
+				})
+			}
+			EGLPlatform::Surfaceless => {
+				require_extension("EGL_MESA_platform_surfaceless")?;
+				let display = get_platform_display(
+					egl,
+					egl_ex::EGL_PLATFORM_SURFACELESS_MESA,
+					std::ptr::null_mut(),
+				)?;
+				Ok(OpenedDisplay {
+					display,
+					device: None,
+					gbm_device: None,
+				})
+			}
+			EGLPlatform::Gbm => {
+				if !has_extension("EGL_KHR_platform_gbm") {
+					require_extension("EGL_MESA_platform_gbm")?;
+				}
+
+				let device = find_render_nodes()
+					.into_iter()
+					.filter_map(|node| RenderDevice::new(Some(node), None).ok())
+					.find(|device| preferred_device.map_or(true, |selector| device.matches(selector)))
+					.ok_or(anyhow!("No matching DRM render node found"))?;
+
+				let gbm_device = open_gbm_device(&device.render_node)?;
+
+				log::info!("Using GBM device {}", device.render_node.display());
+				let display = get_platform_display(
+					egl,
+					egl_ex::EGL_PLATFORM_GBM_KHR,
+					gbm_device.as_raw() as *mut std::ffi::c_void,
+				)?;
+				Ok(OpenedDisplay {
+					display,
+					device: Some(device),
+					gbm_device: Some(gbm_device),
+				})
+			}
+			EGLPlatform::DefaultDisplay => {
+				let display = egl
+					.get_display(khronos_egl::DEFAULT_DISPLAY)
+					.ok_or(anyhow!("eglGetDisplay failed"))?;
+				Ok(OpenedDisplay {
+					display,
+					device: None,
+					gbm_device: None,
+				})
+			}
+		}
+	}
+
+	pub fn new(
+		platform: EGLPlatform,
+		preferred_device: Option<&DeviceSelector>,
+	) -> anyhow::Result<EGLData> {
 		unsafe {
 			let egl = khronos_egl::Instance::new(khronos_egl::Static);
+			let client_extensions = get_client_extensions(&egl);
+			let has_device_query = client_extensions
+				.iter()
+				.any(|ext| ext == "EGL_EXT_device_query" || ext == "EGL_EXT_device_base");
+
+			let candidates = match platform {
+				EGLPlatform::Auto => AUTO_PLATFORMS.to_vec(),
+				platform => vec![platform],
+			};
+
+			let mut opened = None;
+			for candidate in candidates {
+				let mut res =
+					match Self::open_display(&egl, candidate, &client_extensions, preferred_device) {
+						Ok(res) => res,
+						Err(e) => {
+							log::debug!("EGL platform {:?} unavailable: {}", candidate, e);
+							continue;
+						}
+					};
+
+				let (major, minor) = match egl.initialize(res.display) {
+					Ok(version) => version,
+					Err(e) => {
+						log::debug!("eglInitialize failed on platform {:?}: {}", candidate, e);
+						continue;
+					}
+				};
+
+				if res.device.is_none() && has_device_query {
+					res.device = query_display_device(&egl, res.display).ok();
+				}
+
+				// Surfaceless and default displays pick the GPU on their own
+				if let Some(selector) = preferred_device {
+					if !res
+						.device
+						.as_ref()
+						.is_some_and(|device| device.matches(selector))
+					{
+						log::debug!(
+							"EGL platform {:?} doesn't render on the preferred device",
+							candidate
+						);
+						let _ = egl.terminate(res.display);
+						continue;
+					}
+				}
 
-			let display = egl
-				.get_display(khronos_egl::DEFAULT_DISPLAY)
-				.ok_or(anyhow!("eglGetDisplay failed"))?;
+				log::info!("Using EGL platform {:?}", candidate);
+				log::debug!("EGL version: {}.{}", major, minor);
+				opened = Some((candidate, res));
+				break;
+			}
 
-			let (major, minor) = egl.initialize(display)?;
-			log::debug!("EGL version: {}.{}", major, minor);
+			let (platform, opened) = opened.ok_or(anyhow!("No usable EGL platform found"))?;
+			let display = opened.display;
+
+			let gbm_device = match (opened.gbm_device, &opened.device) {
+				(Some(gbm_device), _) => Some(gbm_device),
+				(None, Some(device)) => match open_gbm_device(&device.render_node) {
+					Ok(gbm_device) => Some(gbm_device),
+					Err(e) => {
+						log::warn!(
+							"Failed to open GBM device, format negotiation unavailable: {}",
+							e
+						);
+						None
+					}
+				},
+				(None, None) => None,
+			};
+
+			match &opened.device {
+				Some(device) => log::info!(
+					"Rendering on {} ({}, dev_t {:#x})",
+					device.render_node.display(),
+					device.driver,
+					device.render_dev_t
+				),
+				None => log::warn!("Unable to determine the DRM device used for rendering"),
+			}
 
+			// Rendering only happens into textures, so no surface type is required
 			let attrib_list = [
 				khronos_egl::RED_SIZE,
 				8,
@@ -57,9 +608,9 @@ impl EGLData {
 				khronos_egl::BLUE_SIZE,
 				8,
 				khronos_egl::SURFACE_TYPE,
-				khronos_egl::WINDOW_BIT,
+				0,
 				khronos_egl::RENDERABLE_TYPE,
-				khronos_egl::OPENGL_BIT,
+				khronos_egl::OPENGL_ES3_BIT,
 				khronos_egl::NONE,
 			];
 
@@ -82,17 +633,30 @@ impl EGLData {
 
 			let context = egl.create_context(display, config, None, &context_attrib_list)?;
 
+			// No surface needed (EGL_KHR_surfaceless_context)
 			egl.make_current(display, None, None, Some(context))?;
 
+			let gl_info = query_gl_info(&egl)?;
+			let quirks = quirks::ModifierQuirks::load(&gl_info);
+
 			Ok(EGLData {
 				egl,
 				display,
 				config,
 				context,
+				platform,
+				device: opened.device,
+				gl_info,
+				quirks,
+				gbm_device,
 			})
 		}
 	}
 
+	pub fn get_gbm_device(&self) -> Option<&GbmDevice<File>> {
+		self.gbm_device.as_ref()
+	}
+
 	#[allow(dead_code)]
 	pub fn make_current(&self, surface: &khronos_egl::Surface) -> anyhow::Result<()> {
 		self.egl.make_current(
@@ -105,9 +669,8 @@ impl EGLData {
 		Ok(())
 	}
 
-	fn query_dmabuf_mod_info(&self) -> anyhow::Result<DMAbufModifierInfo> {
-		let target_fourcc = 0x34324258; //XB24
-
+	// Modifiers supported for the given format, e.g. XB24 (0x34324258)
+	fn query_dmabuf_mod_info(&self, target_fourcc: i32) -> anyhow::Result<DMAbufModifierInfo> {
 		unsafe {
 			use egl_ex::PFNEGLQUERYDMABUFFORMATSEXTPROC;
 			use egl_ex::PFNEGLQUERYDMABUFMODIFIERSEXTPROC;
@@ -188,21 +751,7 @@ impl EGLData {
 				log::trace!("{:#x}", modifier);
 			}
 
-			// We should not change these modifier values. Passing all of them to the Vulkan dmabuf
-			// texture system causes significant graphical corruption due to invalid memory layout and
-			// tiling on this specific GPU model (very probably others also have the same issue).
-			// It is not guaranteed that this modifier will be present in other models.
-			// If not, the full list of modifiers will be passed. Further testing is required.
-			// For now, it looks like only NAVI32-based gpus have this problem.
-			let mod_whitelist: [u64; 1] = [0x20000002086bf04 /* AMD RX 7800 XT */];
-
-			for modifier in &mod_whitelist {
-				if mods.contains(modifier) {
-					log::warn!("Using whitelisted dmabuf tiling modifier: {:#x}", modifier);
-					mods = vec![*modifier, 0x0 /* also important (???) */];
-					break;
-				}
-			}
+			let mods = self.quirks.apply(&mods);
 
 			Ok(DMAbufModifierInfo {
 				modifiers: mods,
@@ -212,14 +761,51 @@ impl EGLData {
 	}
 
 	pub fn create_dmabuf_data(&self, egl_image: &khronos_egl::Image) -> anyhow::Result<DMAbufData> {
-		use egl_ex::PFNEGLEXPORTDMABUFIMAGEMESAPROC as FUNC;
+		use egl_ex::PFNEGLEXPORTDMABUFIMAGEMESAPROC;
+		use egl_ex::PFNEGLEXPORTDMABUFIMAGEQUERYMESAPROC;
 		unsafe {
-			let egl_export_dmabuf_image_mesa =
-				bind_egl_function!(FUNC, &self.load_func("eglExportDMABUFImageMESA")?);
+			let egl_export_dmabuf_image_query_mesa = bind_egl_function!(
+				PFNEGLEXPORTDMABUFIMAGEQUERYMESAPROC,
+				&self.load_func("eglExportDMABUFImageQueryMESA")?
+			);
+
+			let egl_export_dmabuf_image_mesa = bind_egl_function!(
+				PFNEGLEXPORTDMABUFIMAGEMESAPROC,
+				&self.load_func("eglExportDMABUFImageMESA")?
+			);
 
-			let mut fds: [i32; 3] = [0; 3];
-			let mut strides: [i32; 3] = [0; 3];
-			let mut offsets: [i32; 3] = [0; 3];
+			let mut fourcc: i32 = 0;
+			let mut num_planes: i32 = 0;
+			let mut modifiers: [u64; MAX_DMABUF_PLANES] = [0; MAX_DMABUF_PLANES];
+
+			// Modifiers are written for every plane, so the plane count has to be queried first
+			if egl_export_dmabuf_image_query_mesa(
+				self.display.as_ptr(),
+				egl_image.as_ptr(),
+				&mut fourcc,
+				&mut num_planes,
+				std::ptr::null_mut(),
+			) != khronos_egl::TRUE
+			{
+				anyhow::bail!("eglExportDMABUFImageQueryMESA failed");
+			}
+
+			let num_planes = num_planes as usize;
+			if num_planes == 0 || num_planes > MAX_DMABUF_PLANES {
+				anyhow::bail!("Unsupported dma-buf plane count {}", num_planes);
+			}
+
+			egl_export_dmabuf_image_query_mesa(
+				self.display.as_ptr(),
+				egl_image.as_ptr(),
+				&mut fourcc,
+				std::ptr::null_mut(),
+				modifiers.as_mut_ptr(),
+			);
+
+			let mut fds: [i32; MAX_DMABUF_PLANES] = [-1; MAX_DMABUF_PLANES];
+			let mut strides: [i32; MAX_DMABUF_PLANES] = [0; MAX_DMABUF_PLANES];
+			let mut offsets: [i32; MAX_DMABUF_PLANES] = [0; MAX_DMABUF_PLANES];
 
 			if egl_export_dmabuf_image_mesa(
 				self.display.as_ptr(),
@@ -232,18 +818,46 @@ impl EGLData {
 				anyhow::bail!("eglExportDMABUFImageMESA failed");
 			}
 
-			// many planes in RGB data?
-			debug_assert!(fds[1] == 0);
-			debug_assert!(strides[1] == 0);
-			debug_assert!(offsets[1] == 0);
+			// The exported fds are ours to close
+			let owned_fds: Vec<Option<OwnedFd>> = fds
+				.iter()
+				.map(|fd| (*fd >= 0).then(|| OwnedFd::from_raw_fd(*fd)))
+				.collect();
+
+			// Planes may share a single fd, in which case the following ones are -1
+			let mut planes = Vec::new();
+			for idx in 0..num_planes {
+				let fd = match (&owned_fds[idx], &owned_fds[0]) {
+					(Some(fd), _) | (None, Some(fd)) => fd.try_clone()?,
+					(None, None) => anyhow::bail!("eglExportDMABUFImageMESA returned no fd"),
+				};
+
+				planes.push(DMAbufPlane {
+					fd,
+					stride: strides[idx],
+					offset: offsets[idx],
+				});
+			}
+
+			let modifier = match modifiers[0] {
+				DRM_FORMAT_MOD_INVALID => None,
+				modifier => Some(modifier),
+			};
+
+			log::debug!(
+				"Exported dma-buf with {} plane(s), modifier {:?}",
+				num_planes,
+				modifier.map(|modifier| format!("{:#x}", modifier))
+			);
 
-			let mod_info = self.query_dmabuf_mod_info()?;
+			// Reported along with the modifiers of the format the driver exported
+			let mod_info = self.query_dmabuf_mod_info(fourcc)?;
 
 			Ok(DMAbufData {
-				fd: fds[0],
-				stride: strides[0],
-				offset: offsets[0],
+				planes,
 				mod_info,
+				modifier,
+				device: self.device.clone(),
 			})
 		}
 	}
@@ -30,3 +30,59 @@ pub type PFNEGLQUERYDMABUFFORMATSEXTPROC = Option<
 		num_formats: *mut khronos_egl::Int,
 	) -> khronos_egl::Boolean,
 >;
+
+pub type EGLDeviceEXT = *mut std::ffi::c_void;
+
+//eglGetPlatformDisplayEXT
+pub type PFNEGLGETPLATFORMDISPLAYEXTPROC = Option<
+	unsafe extern "C" fn(
+		platform: khronos_egl::Enum,
+		native_display: *mut std::ffi::c_void,
+		attrib_list: *const khronos_egl::Int,
+	) -> khronos_egl::EGLDisplay,
+>;
+
+//eglQueryDevicesEXT
+pub type PFNEGLQUERYDEVICESEXTPROC = Option<
+	unsafe extern "C" fn(
+		max_devices: khronos_egl::Int,
+		devices: *mut EGLDeviceEXT,
+		num_devices: *mut khronos_egl::Int,
+	) -> khronos_egl::Boolean,
+>;
+
+//eglQueryDeviceStringEXT
+pub type PFNEGLQUERYDEVICESTRINGEXTPROC = Option<
+	unsafe extern "C" fn(device: EGLDeviceEXT, name: khronos_egl::Int) -> *const std::ffi::c_char,
+>;
+
+pub const EGL_PLATFORM_DEVICE_EXT: khronos_egl::Enum = 0x313F;
+pub const EGL_PLATFORM_GBM_KHR: khronos_egl::Enum = 0x31D7;
+pub const EGL_PLATFORM_SURFACELESS_MESA: khronos_egl::Enum = 0x31DD;
+pub const EGL_DRM_RENDER_NODE_FILE_EXT: khronos_egl::Int = 0x3377;
+
+//eglQueryDisplayAttribEXT
+pub type PFNEGLQUERYDISPLAYATTRIBEXTPROC = Option<
+	unsafe extern "C" fn(
+		dpy: khronos_egl::EGLDisplay,
+		attribute: khronos_egl::Int,
+		value: *mut khronos_egl::Attrib,
+	) -> khronos_egl::Boolean,
+>;
+
+pub const EGL_DEVICE_EXT: khronos_egl::Int = 0x322C;
+pub const EGL_DRM_DEVICE_FILE_EXT: khronos_egl::Int = 0x3233;
+
+//glGetString
+pub type PFNGLGETSTRINGPROC = Option<unsafe extern "C" fn(name: u32) -> *const std::ffi::c_char>;
+
+//eglExportDMABUFImageQueryMESA
+pub type PFNEGLEXPORTDMABUFIMAGEQUERYMESAPROC = Option<
+	unsafe extern "C" fn(
+		dpy: khronos_egl::EGLDisplay,
+		image: khronos_egl::EGLImage,
+		fourcc: *mut i32,
+		num_planes: *mut i32,
+		modifiers: *mut u64,
+	) -> khronos_egl::Boolean,
+>;
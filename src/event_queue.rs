@@ -1,32 +1,36 @@
 #![allow(dead_code)]
 
-use std::{cell::RefCell, collections::VecDeque, rc::Rc};
+use std::{
+	collections::VecDeque,
+	sync::{Arc, Mutex},
+};
 
 struct Data<DataType> {
 	queue: VecDeque<DataType>,
 }
 
+// Thread-safe, so it can also be shared with wayland-server client data
 #[derive(Clone)]
 pub struct SyncEventQueue<DataType> {
-	data: Rc<RefCell<Data<DataType>>>,
+	data: Arc<Mutex<Data<DataType>>>,
 }
 
 impl<DataType> SyncEventQueue<DataType> {
 	pub fn new() -> Self {
 		Self {
-			data: Rc::new(RefCell::new(Data {
+			data: Arc::new(Mutex::new(Data {
 				queue: Default::default(),
 			})),
 		}
 	}
 
 	pub fn send(&self, message: DataType) {
-		let mut data = self.data.borrow_mut();
+		let mut data = self.data.lock().unwrap();
 		data.queue.push_back(message);
 	}
 
 	pub fn read(&self) -> Option<DataType> {
-		let mut data = self.data.borrow_mut();
+		let mut data = self.data.lock().unwrap();
 		data.queue.pop_front()
 	}
 }
@@ -0,0 +1,518 @@
+use crate::window;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct LayoutRect {
+	pub x: i32,
+	pub y: i32,
+	pub w: u32,
+	pub h: u32,
+}
+
+pub struct LayoutWindow {
+	pub handle: window::WindowHandle,
+	// Current geometry, zero-sized if the window was never laid out yet
+	pub rect: LayoutRect,
+	// Size constraints requested by the client, 0 means unconstrained
+	pub min_size: (u32, u32),
+	pub max_size: (u32, u32),
+}
+
+impl LayoutWindow {
+	pub fn clamp_size(&self, w: u32, h: u32) -> (u32, u32) {
+		let clamp = |val: u32, min: u32, max: u32| {
+			let val = if max > 0 { val.min(max) } else { val };
+			val.max(min)
+		};
+
+		(
+			clamp(w, self.min_size.0, self.max_size.0),
+			clamp(h, self.min_size.1, self.max_size.1),
+		)
+	}
+
+	// Fits the window into a tile, centering it if its maximum size is smaller than the tile
+	pub fn fit(&self, tile: LayoutRect) -> LayoutRect {
+		let (w, h) = self.clamp_size(tile.w, tile.h);
+		LayoutRect {
+			x: tile.x + (tile.w as i32 - w as i32).max(0) / 2,
+			y: tile.y + (tile.h as i32 - h as i32).max(0) / 2,
+			w,
+			h,
+		}
+	}
+}
+
+pub trait Layout {
+	// Returns new geometry for every window, in the same order. None hides the window.
+	fn arrange(
+		&mut self,
+		windows: &[LayoutWindow],
+		width: u32,
+		height: u32,
+	) -> Vec<Option<LayoutRect>>;
+
+	// Called when the window at this index gets selected (e.g. tab switching)
+	fn activate(&mut self, _index: usize) {}
+
+	// Called when the window at this index is gone, later windows move one index down
+	fn remove(&mut self, _index: usize) {}
+
+	// Floating layouts allow overlapping windows, raising and interactive move/resize
+	fn is_floating(&self) -> bool {
+		false
+	}
+}
+
+// Start and length of the n-th of count equal parts
+fn split(total: u32, count: usize, idx: usize) -> (i32, u32) {
+	let d_cur = idx as f32 / count as f32;
+	let d_next = (idx + 1) as f32 / count as f32;
+
+	let start = (d_cur * total as f32) as i32;
+	let end = (d_next * total as f32) as i32;
+
+	(start, (end - start) as u32)
+}
+
+// Equal-width vertical columns
+#[derive(Default)]
+pub struct ColumnsLayout {}
+
+impl Layout for ColumnsLayout {
+	fn arrange(
+		&mut self,
+		windows: &[LayoutWindow],
+		width: u32,
+		height: u32,
+	) -> Vec<Option<LayoutRect>> {
+		windows
+			.iter()
+			.enumerate()
+			.map(|(i, win)| {
+				let (x, w) = split(width, windows.len(), i);
+				Some(win.fit(LayoutRect {
+					x,
+					y: 0,
+					w,
+					h: height,
+				}))
+			})
+			.collect()
+	}
+}
+
+// Equal-height horizontal rows
+#[derive(Default)]
+pub struct RowsLayout {}
+
+impl Layout for RowsLayout {
+	fn arrange(
+		&mut self,
+		windows: &[LayoutWindow],
+		width: u32,
+		height: u32,
+	) -> Vec<Option<LayoutRect>> {
+		windows
+			.iter()
+			.enumerate()
+			.map(|(i, win)| {
+				let (y, h) = split(height, windows.len(), i);
+				Some(win.fit(LayoutRect {
+					x: 0,
+					y,
+					w: width,
+					h,
+				}))
+			})
+			.collect()
+	}
+}
+
+// First window on the left, the rest stacked in rows on the right
+pub struct MasterStackLayout {
+	pub master_ratio: f32,
+}
+
+impl Default for MasterStackLayout {
+	fn default() -> Self {
+		Self { master_ratio: 0.5 }
+	}
+}
+
+impl Layout for MasterStackLayout {
+	fn arrange(
+		&mut self,
+		windows: &[LayoutWindow],
+		width: u32,
+		height: u32,
+	) -> Vec<Option<LayoutRect>> {
+		if windows.len() == 1 {
+			return vec![Some(windows[0].fit(LayoutRect {
+				x: 0,
+				y: 0,
+				w: width,
+				h: height,
+			}))];
+		}
+
+		let master_width = (width as f32 * self.master_ratio.clamp(0.0, 1.0)) as u32;
+		let stack_count = windows.len().saturating_sub(1);
+
+		windows
+			.iter()
+			.enumerate()
+			.map(|(i, win)| {
+				if i == 0 {
+					return Some(win.fit(LayoutRect {
+						x: 0,
+						y: 0,
+						w: master_width,
+						h: height,
+					}));
+				}
+
+				let (y, h) = split(height, stack_count, i - 1);
+				Some(win.fit(LayoutRect {
+					x: master_width as i32,
+					y,
+					w: width - master_width,
+					h,
+				}))
+			})
+			.collect()
+	}
+}
+
+// Roughly square grid, filled row by row
+#[derive(Default)]
+pub struct GridLayout {}
+
+impl Layout for GridLayout {
+	fn arrange(
+		&mut self,
+		windows: &[LayoutWindow],
+		width: u32,
+		height: u32,
+	) -> Vec<Option<LayoutRect>> {
+		if windows.is_empty() {
+			return Vec::new();
+		}
+
+		let cols = (windows.len() as f32).sqrt().ceil() as usize;
+		let rows = windows.len().div_ceil(cols);
+
+		windows
+			.iter()
+			.enumerate()
+			.map(|(i, win)| {
+				let (x, w) = split(width, cols, i % cols);
+				let (y, h) = split(height, rows, i / cols);
+				Some(win.fit(LayoutRect { x, y, w, h }))
+			})
+			.collect()
+	}
+}
+
+// A single window covering the whole display, others are hidden until activated
+#[derive(Default)]
+pub struct MaximizedLayout {
+	active: usize,
+}
+
+impl Layout for MaximizedLayout {
+	fn arrange(
+		&mut self,
+		windows: &[LayoutWindow],
+		width: u32,
+		height: u32,
+	) -> Vec<Option<LayoutRect>> {
+		if self.active >= windows.len() {
+			self.active = windows.len().saturating_sub(1);
+		}
+
+		windows
+			.iter()
+			.enumerate()
+			.map(|(i, win)| {
+				if i != self.active {
+					return None;
+				}
+				Some(win.fit(LayoutRect {
+					x: 0,
+					y: 0,
+					w: width,
+					h: height,
+				}))
+			})
+			.collect()
+	}
+
+	fn activate(&mut self, index: usize) {
+		self.active = index;
+	}
+
+	// Keeps the same window active, removing the active one activates the next
+	fn remove(&mut self, index: usize) {
+		if index < self.active {
+			self.active -= 1;
+		}
+	}
+}
+
+// Windows keep their geometry, new ones get centered
+#[derive(Default)]
+pub struct FloatingLayout {}
+
+impl Layout for FloatingLayout {
+	fn arrange(
+		&mut self,
+		windows: &[LayoutWindow],
+		width: u32,
+		height: u32,
+	) -> Vec<Option<LayoutRect>> {
+		windows
+			.iter()
+			.map(|win| {
+				if win.rect.w > 0 && win.rect.h > 0 {
+					return Some(win.rect);
+				}
+
+				let (w, h) = win.clamp_size(width * 3 / 4, height * 3 / 4);
+				Some(LayoutRect {
+					x: (width as i32 - w as i32) / 2,
+					y: (height as i32 - h as i32) / 2,
+					w,
+					h,
+				})
+			})
+			.collect()
+	}
+
+	fn is_floating(&self) -> bool {
+		true
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const WIDTH: u32 = 101;
+	const HEIGHT: u32 = 77;
+
+	fn window() -> LayoutWindow {
+		LayoutWindow {
+			handle: window::WindowHandle::default(),
+			rect: LayoutRect {
+				x: 0,
+				y: 0,
+				w: 0,
+				h: 0,
+			},
+			min_size: (0, 0),
+			max_size: (0, 0),
+		}
+	}
+
+	fn windows(count: usize) -> Vec<LayoutWindow> {
+		(0..count).map(|_| window()).collect()
+	}
+
+	fn full() -> LayoutRect {
+		LayoutRect {
+			x: 0,
+			y: 0,
+			w: WIDTH,
+			h: HEIGHT,
+		}
+	}
+
+	fn all_layouts() -> Vec<Box<dyn Layout>> {
+		vec![
+			Box::new(ColumnsLayout::default()),
+			Box::new(RowsLayout::default()),
+			Box::new(MasterStackLayout::default()),
+			Box::new(GridLayout::default()),
+			Box::new(MaximizedLayout::default()),
+			Box::new(FloatingLayout::default()),
+		]
+	}
+
+	fn unwrap_all(rects: Vec<Option<LayoutRect>>) -> Vec<LayoutRect> {
+		rects.into_iter().map(|rect| rect.unwrap()).collect()
+	}
+
+	#[test]
+	fn no_windows() {
+		for mut layout in all_layouts() {
+			assert!(layout.arrange(&[], WIDTH, HEIGHT).is_empty());
+		}
+	}
+
+	#[test]
+	fn single_window_fills_display() {
+		for mut layout in all_layouts() {
+			if layout.is_floating() {
+				continue;
+			}
+			assert_eq!(
+				layout.arrange(&windows(1), WIDTH, HEIGHT),
+				vec![Some(full())]
+			);
+		}
+	}
+
+	#[test]
+	fn columns() {
+		let rects = unwrap_all(ColumnsLayout::default().arrange(&windows(3), WIDTH, HEIGHT));
+		assert_eq!(rects.len(), 3);
+
+		// Adjacent, no gaps, covering the odd width exactly
+		let mut x = 0;
+		for rect in &rects {
+			assert_eq!(rect.x, x);
+			assert_eq!((rect.y, rect.h), (0, HEIGHT));
+			x += rect.w as i32;
+		}
+		assert_eq!(x, WIDTH as i32);
+	}
+
+	#[test]
+	fn rows() {
+		let rects = unwrap_all(RowsLayout::default().arrange(&windows(3), WIDTH, HEIGHT));
+		assert_eq!(rects.len(), 3);
+
+		let mut y = 0;
+		for rect in &rects {
+			assert_eq!(rect.y, y);
+			assert_eq!((rect.x, rect.w), (0, WIDTH));
+			y += rect.h as i32;
+		}
+		assert_eq!(y, HEIGHT as i32);
+	}
+
+	#[test]
+	fn master_stack() {
+		let rects = unwrap_all(MasterStackLayout::default().arrange(&windows(4), WIDTH, HEIGHT));
+		assert_eq!(rects.len(), 4);
+
+		let master = rects[0];
+		assert_eq!((master.x, master.y, master.h), (0, 0, HEIGHT));
+
+		let mut y = 0;
+		for rect in &rects[1..] {
+			assert_eq!(rect.x, master.w as i32);
+			assert_eq!(rect.w, WIDTH - master.w);
+			assert_eq!(rect.y, y);
+			y += rect.h as i32;
+		}
+		assert_eq!(y, HEIGHT as i32);
+	}
+
+	#[test]
+	fn grid() {
+		// 5 windows: 3 columns, 2 rows
+		let rects = unwrap_all(GridLayout::default().arrange(&windows(5), WIDTH, HEIGHT));
+		assert_eq!(rects.len(), 5);
+
+		for (i, rect) in rects.iter().enumerate() {
+			assert!(rect.x >= 0 && rect.x as u32 + rect.w <= WIDTH);
+			assert!(rect.y >= 0 && rect.y as u32 + rect.h <= HEIGHT);
+			assert_eq!(rect.y == 0, i < 3);
+
+			for other in &rects[i + 1..] {
+				let overlap_x = rect.x < other.x + other.w as i32 && other.x < rect.x + rect.w as i32;
+				let overlap_y = rect.y < other.y + other.h as i32 && other.y < rect.y + rect.h as i32;
+				assert!(!(overlap_x && overlap_y));
+			}
+		}
+
+		assert_eq!(rects[2].x as u32 + rects[2].w, WIDTH);
+		assert_eq!(rects[3].y as u32 + rects[3].h, HEIGHT);
+	}
+
+	#[test]
+	fn maximized() {
+		let mut layout = MaximizedLayout::default();
+		layout.activate(2);
+
+		let rects = layout.arrange(&windows(3), WIDTH, HEIGHT);
+		assert_eq!(rects, vec![None, None, Some(full())]);
+
+		// Out of range after windows are gone, falls back to the last one
+		let rects = layout.arrange(&windows(2), WIDTH, HEIGHT);
+		assert_eq!(rects, vec![None, Some(full())]);
+	}
+
+	#[test]
+	fn maximized_remove() {
+		let mut layout = MaximizedLayout::default();
+		layout.activate(2);
+
+		// A window before the active one is gone, the same window stays active
+		layout.remove(0);
+		let rects = layout.arrange(&windows(3), WIDTH, HEIGHT);
+		assert_eq!(rects, vec![None, Some(full()), None]);
+
+		// Windows after it don't matter
+		layout.remove(2);
+		let rects = layout.arrange(&windows(2), WIDTH, HEIGHT);
+		assert_eq!(rects, vec![None, Some(full())]);
+	}
+
+	#[test]
+	fn floating() {
+		let mut placed = window();
+		placed.rect = LayoutRect {
+			x: -10,
+			y: 5,
+			w: 40,
+			h: 30,
+		};
+
+		let rects = unwrap_all(FloatingLayout::default().arrange(&[window(), placed], WIDTH, HEIGHT));
+
+		// New windows are centered at 3/4 of the display
+		let (w, h) = (WIDTH * 3 / 4, HEIGHT * 3 / 4);
+		assert_eq!(
+			rects[0],
+			LayoutRect {
+				x: (WIDTH - w) as i32 / 2,
+				y: (HEIGHT - h) as i32 / 2,
+				w,
+				h,
+			}
+		);
+
+		// Placed windows keep their geometry, even partially off the display
+		assert_eq!(
+			rects[1],
+			LayoutRect {
+				x: -10,
+				y: 5,
+				w: 40,
+				h: 30,
+			}
+		);
+	}
+
+	#[test]
+	fn size_constraints() {
+		let mut win = window();
+		win.min_size = (10, 200);
+		win.max_size = (51, 0);
+
+		let rects = unwrap_all(ColumnsLayout::default().arrange(&[win], WIDTH, HEIGHT));
+
+		// Too narrow tiles get centered, minimum size wins over the display size
+		assert_eq!(
+			rects[0],
+			LayoutRect {
+				x: 25,
+				y: 0,
+				w: 51,
+				h: 200,
+			}
+		);
+	}
+}
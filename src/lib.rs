@@ -1,20 +1,44 @@
+pub mod capture;
 mod client;
 mod comp;
+mod decoration;
 pub mod display;
 pub mod egl_data;
 mod egl_ex;
 mod event_queue;
 mod id;
+pub mod layout;
+mod output;
+mod quirks;
+mod readback;
+mod render;
+mod screencopy;
 mod smithay_wrapper;
+mod swapchain;
 mod time;
 pub mod wayvr;
-mod window;
+pub mod window;
 
 pub use khronos_egl;
 
 #[cfg(test)]
 mod tests {
 	use crate::wayvr;
+	use smithay::reexports::wayland_server::protocol::wl_shm;
+
+	fn init_logger() {
+		static INIT: std::sync::Once = std::sync::Once::new();
+		INIT.call_once(|| {
+			flexi_logger::Logger::try_with_env_or_str("info, wayvr=trace")
+				.unwrap()
+				.start()
+				.unwrap();
+		});
+	}
+
+	fn count_open_fds() -> usize {
+		std::fs::read_dir("/proc/self/fd").unwrap().count()
+	}
 
 	fn run() -> Result<(), Box<dyn std::error::Error>> {
 		let mut wayvr = wayvr::WayVR::new()?;
@@ -33,6 +57,10 @@ mod tests {
 
 			wayvr.tick_events()?;
 
+			while let Some(event) = wayvr.poll_event() {
+				log::debug!("Event: {:?}", event);
+			}
+
 			std::thread::sleep(std::time::Duration::from_millis(50))
 		}
 
@@ -41,7 +69,696 @@ mod tests {
 
 	#[test]
 	fn test() -> std::result::Result<(), Box<dyn std::error::Error>> {
-		flexi_logger::Logger::try_with_env_or_str("info, wayvr=trace")?.start()?;
+		init_logger();
 		run()
 	}
+
+	#[test]
+	fn display_create_destroy_leak() -> std::result::Result<(), Box<dyn std::error::Error>> {
+		init_logger();
+		let mut wayvr = wayvr::WayVR::new()?;
+
+		let cycle = |wayvr: &mut wayvr::WayVR| -> anyhow::Result<()> {
+			let disp = wayvr.create_display(256, 256)?;
+			wayvr.tick_display(disp)?;
+			drop(wayvr.get_display_output(disp)?);
+			wayvr.destroy_display(disp);
+			Ok(())
+		};
+
+		// Warm up lazily created driver resources
+		for _ in 0..10 {
+			cycle(&mut wayvr)?;
+		}
+
+		let fd_count = count_open_fds();
+
+		for _ in 0..300 {
+			cycle(&mut wayvr)?;
+		}
+
+		assert_eq!(count_open_fds(), fd_count);
+		Ok(())
+	}
+
+	#[test]
+	fn display_resize_leak() -> std::result::Result<(), Box<dyn std::error::Error>> {
+		init_logger();
+		let mut wayvr = wayvr::WayVR::new()?;
+		let disp = wayvr.create_display(256, 256)?;
+
+		let cycle = |wayvr: &mut wayvr::WayVR, size: u32| -> anyhow::Result<()> {
+			wayvr.resize_display(disp, size, size)?;
+			wayvr.tick_display(disp)?;
+			drop(wayvr.get_display_output(disp)?);
+			while wayvr.poll_event().is_some() {}
+			Ok(())
+		};
+
+		for i in 0..10 {
+			cycle(&mut wayvr, 128 + i * 16)?;
+		}
+
+		let fd_count = count_open_fds();
+
+		for i in 0..300 {
+			cycle(&mut wayvr, 128 + (i % 10) * 16)?;
+		}
+
+		assert_eq!(count_open_fds(), fd_count);
+		wayvr.destroy_display(disp);
+		Ok(())
+	}
+
+	// Wayland client run in a child process, the compositor only accepts clients it spawned
+	mod shm_client {
+		use std::{io::Write, os::fd::AsFd};
+
+		use wayland_client::{
+			delegate_noop,
+			protocol::{wl_buffer, wl_compositor, wl_registry, wl_shm, wl_shm_pool, wl_surface},
+			Connection, Dispatch, QueueHandle, WEnum,
+		};
+		use wayland_protocols::xdg::shell::client::{xdg_surface, xdg_toplevel, xdg_wm_base};
+
+		#[derive(Default)]
+		struct State {
+			compositor: Option<wl_compositor::WlCompositor>,
+			shm: Option<wl_shm::WlShm>,
+			wm_base: Option<xdg_wm_base::XdgWmBase>,
+			formats: Vec<wl_shm::Format>,
+			size: (i32, i32),
+			configured: bool,
+		}
+
+		impl Dispatch<wl_registry::WlRegistry, ()> for State {
+			fn event(
+				state: &mut Self,
+				registry: &wl_registry::WlRegistry,
+				event: wl_registry::Event,
+				_: &(),
+				_: &Connection,
+				qh: &QueueHandle<Self>,
+			) {
+				if let wl_registry::Event::Global {
+					name, interface, ..
+				} = event
+				{
+					match interface.as_str() {
+						"wl_compositor" => state.compositor = Some(registry.bind(name, 4, qh, ())),
+						"wl_shm" => state.shm = Some(registry.bind(name, 1, qh, ())),
+						"xdg_wm_base" => state.wm_base = Some(registry.bind(name, 1, qh, ())),
+						_ => {}
+					}
+				}
+			}
+		}
+
+		impl Dispatch<wl_shm::WlShm, ()> for State {
+			fn event(
+				state: &mut Self,
+				_: &wl_shm::WlShm,
+				event: wl_shm::Event,
+				_: &(),
+				_: &Connection,
+				_: &QueueHandle<Self>,
+			) {
+				if let wl_shm::Event::Format {
+					format: WEnum::Value(format),
+				} = event
+				{
+					state.formats.push(format);
+				}
+			}
+		}
+
+		impl Dispatch<xdg_wm_base::XdgWmBase, ()> for State {
+			fn event(
+				_: &mut Self,
+				wm_base: &xdg_wm_base::XdgWmBase,
+				event: xdg_wm_base::Event,
+				_: &(),
+				_: &Connection,
+				_: &QueueHandle<Self>,
+			) {
+				if let xdg_wm_base::Event::Ping { serial } = event {
+					wm_base.pong(serial);
+				}
+			}
+		}
+
+		impl Dispatch<xdg_surface::XdgSurface, ()> for State {
+			fn event(
+				state: &mut Self,
+				xdg_surface: &xdg_surface::XdgSurface,
+				event: xdg_surface::Event,
+				_: &(),
+				_: &Connection,
+				_: &QueueHandle<Self>,
+			) {
+				if let xdg_surface::Event::Configure { serial } = event {
+					xdg_surface.ack_configure(serial);
+					state.configured = true;
+				}
+			}
+		}
+
+		impl Dispatch<xdg_toplevel::XdgToplevel, ()> for State {
+			fn event(
+				state: &mut Self,
+				_: &xdg_toplevel::XdgToplevel,
+				event: xdg_toplevel::Event,
+				_: &(),
+				_: &Connection,
+				_: &QueueHandle<Self>,
+			) {
+				if let xdg_toplevel::Event::Configure { width, height, .. } = event {
+					if width > 0 && height > 0 {
+						state.size = (width, height);
+					}
+				}
+			}
+		}
+
+		delegate_noop!(State: ignore wl_compositor::WlCompositor);
+		delegate_noop!(State: ignore wl_surface::WlSurface);
+		delegate_noop!(State: ignore wl_shm_pool::WlShmPool);
+		delegate_noop!(State: ignore wl_buffer::WlBuffer);
+
+		// Opaque orange (1.0, 0.5, 0.0)
+		pub fn encode_pixel(format: wl_shm::Format) -> Option<Vec<u8>> {
+			let pixel = match format {
+				wl_shm::Format::Abgr8888 | wl_shm::Format::Xbgr8888 => vec![255, 128, 0, 255],
+				wl_shm::Format::Argb8888 | wl_shm::Format::Xrgb8888 => vec![0, 128, 255, 255],
+				wl_shm::Format::Rgb565 => ((31u16 << 11) | (32 << 5)).to_le_bytes().to_vec(),
+				wl_shm::Format::Argb2101010 | wl_shm::Format::Xrgb2101010 => {
+					((3u32 << 30) | (1023 << 20) | (512 << 10))
+						.to_le_bytes()
+						.to_vec()
+				}
+				wl_shm::Format::Abgr2101010 | wl_shm::Format::Xbgr2101010 => {
+					((3u32 << 30) | (512 << 10) | 1023).to_le_bytes().to_vec()
+				}
+				wl_shm::Format::Abgr16161616f | wl_shm::Format::Xbgr16161616f => {
+					// Half floats: 1.0 is 0x3c00, 0.5 is 0x3800
+					((0x3c00u64 << 48) | (0x3800 << 16) | 0x3c00)
+						.to_le_bytes()
+						.to_vec()
+				}
+				_ => return None,
+			};
+			Some(pixel)
+		}
+
+		// Shows a single window filled with one color, until the compositor kills the process
+		pub fn run(format: u32) -> Result<(), Box<dyn std::error::Error>> {
+			let format = wl_shm::Format::try_from(format).map_err(|_| "Unknown shm format")?;
+			let pixel = encode_pixel(format).ok_or("No test pixel for this format")?;
+
+			let conn = Connection::connect_to_env()?;
+			let mut queue = conn.new_event_queue();
+			let qh = queue.handle();
+			conn.display().get_registry(&qh, ());
+
+			let mut state = State {
+				size: (64, 64),
+				..Default::default()
+			};
+
+			// Globals first, then their initial events
+			queue.roundtrip(&mut state)?;
+			queue.roundtrip(&mut state)?;
+
+			let (Some(compositor), Some(shm), Some(wm_base)) = (
+				state.compositor.clone(),
+				state.shm.clone(),
+				state.wm_base.clone(),
+			) else {
+				return Err("Missing globals".into());
+			};
+
+			if !state.formats.contains(&format) {
+				return Err(format!("{:?} isn't advertised", format).into());
+			}
+
+			let surface = compositor.create_surface(&qh, ());
+			let xdg_surface = wm_base.get_xdg_surface(&surface, &qh, ());
+			let toplevel = xdg_surface.get_toplevel(&qh, ());
+			toplevel.set_title(String::from("shm format test"));
+			surface.commit();
+
+			while !state.configured {
+				queue.blocking_dispatch(&mut state)?;
+			}
+
+			let (width, height) = state.size;
+			let stride = width * pixel.len() as i32;
+			let data: Vec<u8> = pixel
+				.iter()
+				.copied()
+				.cycle()
+				.take((stride * height) as usize)
+				.collect();
+
+			let fd = rustix::fs::memfd_create("wayvr-test", rustix::fs::MemfdFlags::CLOEXEC)?;
+			let mut file = std::fs::File::from(fd);
+			file.write_all(&data)?;
+
+			let pool = shm.create_pool(file.as_fd(), data.len() as i32, &qh, ());
+			let buffer = pool.create_buffer(0, width, height, stride, format, &qh, ());
+
+			surface.attach(Some(&buffer), 0, 0);
+			surface.damage_buffer(0, 0, width, height);
+			surface.commit();
+
+			loop {
+				queue.blocking_dispatch(&mut state)?;
+			}
+		}
+	}
+
+	// Captures the first wl_output with zwlr_screencopy_manager_v1 into a shm buffer
+	mod screencopy_client {
+		use std::{
+			io::{Read, Seek},
+			os::fd::AsFd,
+		};
+
+		use wayland_client::{
+			delegate_noop,
+			protocol::{wl_buffer, wl_output, wl_registry, wl_shm, wl_shm_pool},
+			Connection, Dispatch, QueueHandle, WEnum,
+		};
+		use wayland_protocols_wlr::screencopy::v1::client::{
+			zwlr_screencopy_frame_v1, zwlr_screencopy_manager_v1,
+		};
+
+		#[derive(Default)]
+		struct State {
+			shm: Option<wl_shm::WlShm>,
+			output: Option<wl_output::WlOutput>,
+			manager: Option<zwlr_screencopy_manager_v1::ZwlrScreencopyManagerV1>,
+			buffer: Option<(wl_shm::Format, i32, i32, i32)>, // Format, width, height, stride
+			buffer_done: bool,
+			ready: Option<bool>, // Set once the frame is ready or failed
+		}
+
+		impl Dispatch<wl_registry::WlRegistry, ()> for State {
+			fn event(
+				state: &mut Self,
+				registry: &wl_registry::WlRegistry,
+				event: wl_registry::Event,
+				_: &(),
+				_: &Connection,
+				qh: &QueueHandle<Self>,
+			) {
+				if let wl_registry::Event::Global {
+					name, interface, ..
+				} = event
+				{
+					match interface.as_str() {
+						"wl_shm" => state.shm = Some(registry.bind(name, 1, qh, ())),
+						"wl_output" if state.output.is_none() => {
+							state.output = Some(registry.bind(name, 1, qh, ()))
+						}
+						"zwlr_screencopy_manager_v1" => state.manager = Some(registry.bind(name, 3, qh, ())),
+						_ => {}
+					}
+				}
+			}
+		}
+
+		impl Dispatch<zwlr_screencopy_frame_v1::ZwlrScreencopyFrameV1, ()> for State {
+			fn event(
+				state: &mut Self,
+				_: &zwlr_screencopy_frame_v1::ZwlrScreencopyFrameV1,
+				event: zwlr_screencopy_frame_v1::Event,
+				_: &(),
+				_: &Connection,
+				_: &QueueHandle<Self>,
+			) {
+				match event {
+					zwlr_screencopy_frame_v1::Event::Buffer {
+						format: WEnum::Value(format),
+						width,
+						height,
+						stride,
+					} => state.buffer = Some((format, width as i32, height as i32, stride as i32)),
+					zwlr_screencopy_frame_v1::Event::BufferDone => state.buffer_done = true,
+					zwlr_screencopy_frame_v1::Event::Ready { .. } => state.ready = Some(true),
+					zwlr_screencopy_frame_v1::Event::Failed => state.ready = Some(false),
+					_ => {}
+				}
+			}
+		}
+
+		delegate_noop!(State: ignore wl_shm::WlShm);
+		delegate_noop!(State: ignore wl_output::WlOutput);
+		delegate_noop!(State: ignore wl_shm_pool::WlShmPool);
+		delegate_noop!(State: ignore wl_buffer::WlBuffer);
+		delegate_noop!(State: zwlr_screencopy_manager_v1::ZwlrScreencopyManagerV1);
+
+		// Exits with an error unless the copy ends up as expected.
+		// Successful copies have to contain the orange window of shm_client in the middle.
+		pub fn run(expect_ready: bool) -> Result<(), Box<dyn std::error::Error>> {
+			let conn = Connection::connect_to_env()?;
+			let mut queue = conn.new_event_queue();
+			let qh = queue.handle();
+			conn.display().get_registry(&qh, ());
+
+			let mut state = State::default();
+			queue.roundtrip(&mut state)?;
+
+			let (Some(shm), Some(output), Some(manager)) = (
+				state.shm.clone(),
+				state.output.clone(),
+				state.manager.clone(),
+			) else {
+				return Err("Missing globals".into());
+			};
+
+			let frame = manager.capture_output(0, &output, &qh, ());
+			while !state.buffer_done && state.ready.is_none() {
+				queue.blocking_dispatch(&mut state)?;
+			}
+
+			let (format, width, height, stride) = state.buffer.ok_or("No shm buffer offered")?;
+			if format != wl_shm::Format::Argb8888 {
+				return Err(format!("Unexpected buffer format {:?}", format).into());
+			}
+
+			let fd = rustix::fs::memfd_create("wayvr-test", rustix::fs::MemfdFlags::CLOEXEC)?;
+			let mut file = std::fs::File::from(fd);
+			file.set_len((stride * height) as u64)?;
+
+			let pool = shm.create_pool(file.as_fd(), stride * height, &qh, ());
+			let buffer = pool.create_buffer(0, width, height, stride, format, &qh, ());
+			frame.copy(&buffer);
+
+			while state.ready.is_none() {
+				queue.blocking_dispatch(&mut state)?;
+			}
+
+			match (state.ready, expect_ready) {
+				(Some(true), true) => {}
+				(Some(false), false) => return Ok(()),
+				(ready, _) => return Err(format!("Unexpected copy result {:?}", ready).into()),
+			}
+
+			let mut data = Vec::new();
+			file.rewind()?;
+			file.read_to_end(&mut data)?;
+
+			// Orange (255, 128, 0) in little endian ARGB8888
+			let expected = [0u8, 128, 255, 255];
+			let idx = (height / 2 * stride + width / 2 * 4) as usize;
+			let pixel = &data[idx..idx + 4];
+
+			if !pixel.iter().zip(expected).all(|(a, b)| a.abs_diff(b) <= 8) {
+				return Err(format!("Unexpected pixel {:?}", pixel).into());
+			}
+
+			Ok(())
+		}
+	}
+
+	const SHM_CLIENT_FORMAT: &str = "WAYVR_TEST_SHM_FORMAT";
+	const SCREENCOPY_CLIENT_EXPECT: &str = "WAYVR_TEST_SCREENCOPY_EXPECT";
+
+	#[test]
+	#[ignore = "Spawned by the shm format tests"]
+	fn shm_client() -> std::result::Result<(), Box<dyn std::error::Error>> {
+		let Ok(format) = std::env::var(SHM_CLIENT_FORMAT) else {
+			return Ok(());
+		};
+		shm_client::run(format.parse()?)
+	}
+
+	#[test]
+	#[ignore = "Spawned by the screencopy tests"]
+	fn screencopy_client() -> std::result::Result<(), Box<dyn std::error::Error>> {
+		let Ok(expect) = std::env::var(SCREENCOPY_CLIENT_EXPECT) else {
+			return Ok(());
+		};
+		screencopy_client::run(expect == "ready")
+	}
+
+	// Runs one of the ignored client tests in a child process on the display
+	fn spawn_test_client(
+		wayvr: &mut wayvr::WayVR,
+		disp: crate::display::DisplayHandle,
+		test: &str,
+		env: &[(&str, &str)],
+	) -> std::result::Result<(), Box<dyn std::error::Error>> {
+		let exe = std::env::current_exe()?;
+		wayvr.spawn_process(
+			disp,
+			exe.to_str().ok_or("Invalid test executable path")?,
+			&["--exact", test, "--ignored"],
+			env,
+		)?;
+		Ok(())
+	}
+
+	// Renders the display until the pixel in its middle matches, fails if a client exits meanwhile
+	fn wait_for_pixel(
+		wayvr: &mut wayvr::WayVR,
+		disp: crate::display::DisplayHandle,
+		expected: [u8; 4],
+	) -> std::result::Result<(), Box<dyn std::error::Error>> {
+		for _ in 0..500 {
+			wayvr.tick_events()?;
+			while let Some(event) = wayvr.poll_event() {
+				if let wayvr::WayVREvent::ProcessExited { status, .. } = event {
+					return Err(format!("Client exited with {}", status).into());
+				}
+			}
+
+			wayvr.tick_display(disp)?;
+			wayvr.tick_finish()?;
+
+			let image = wayvr.capture_display(disp)?;
+			let idx = ((image.height / 2 * image.width + image.width / 2) * 4) as usize;
+			let pixel = &image.data[idx..idx + 4];
+
+			if pixel.iter().zip(expected).all(|(a, b)| a.abs_diff(b) <= 8) {
+				return Ok(());
+			}
+
+			std::thread::sleep(std::time::Duration::from_millis(10));
+		}
+
+		Err("Expected pixel wasn't rendered".into())
+	}
+
+	// Renders the display until a client exits
+	fn wait_for_exit(
+		wayvr: &mut wayvr::WayVR,
+		disp: crate::display::DisplayHandle,
+	) -> std::result::Result<std::process::ExitStatus, Box<dyn std::error::Error>> {
+		for _ in 0..500 {
+			wayvr.tick_events()?;
+			while let Some(event) = wayvr.poll_event() {
+				if let wayvr::WayVREvent::ProcessExited { status, .. } = event {
+					return Ok(status);
+				}
+			}
+
+			wayvr.tick_display(disp)?;
+			wayvr.tick_finish()?;
+			std::thread::sleep(std::time::Duration::from_millis(10));
+		}
+
+		Err("Client didn't exit".into())
+	}
+
+	// Renders a window with a buffer in the given format and checks the pixel in the middle of the display
+	fn check_shm_format(
+		format: wl_shm::Format,
+	) -> std::result::Result<(), Box<dyn std::error::Error>> {
+		let mut wayvr = wayvr::WayVR::new()?;
+
+		if !wayvr.get_shm_formats().contains(&format) {
+			return Err(format!("{:?} isn't advertised", format).into());
+		}
+
+		let disp = wayvr.create_display(128, 128)?;
+		spawn_test_client(
+			&mut wayvr,
+			disp,
+			"tests::shm_client",
+			&[(SHM_CLIENT_FORMAT, (format as u32).to_string().as_str())],
+		)?;
+
+		wait_for_pixel(&mut wayvr, disp, [255, 128, 0, 255])
+			.map_err(|e| format!("{:?} buffer wasn't rendered: {}", format, e).into())
+	}
+
+	// Uploaded by every renderer
+	const SHM_FORMATS: [wl_shm::Format; 4] = [
+		wl_shm::Format::Abgr8888,
+		wl_shm::Format::Xbgr8888,
+		wl_shm::Format::Argb8888,
+		wl_shm::Format::Xrgb8888,
+	];
+
+	#[test]
+	fn shm_formats() -> std::result::Result<(), Box<dyn std::error::Error>> {
+		init_logger();
+		for format in SHM_FORMATS {
+			check_shm_format(format)?;
+		}
+		Ok(())
+	}
+
+	// Formats beyond 8888 depend on the renderer (GLES needs the matching texture type extensions),
+	// every advertised one the test client can fill is checked
+	#[test]
+	fn shm_formats_extended() -> std::result::Result<(), Box<dyn std::error::Error>> {
+		init_logger();
+		let advertised = wayvr::WayVR::new()?.get_shm_formats();
+
+		for format in advertised {
+			if SHM_FORMATS.contains(&format) {
+				continue;
+			}
+			if shm_client::encode_pixel(format).is_none() {
+				log::info!("Skipping {:?}, the test client can't fill it", format);
+				continue;
+			}
+			check_shm_format(format)?;
+		}
+		Ok(())
+	}
+
+	#[test]
+	fn screencopy() -> std::result::Result<(), Box<dyn std::error::Error>> {
+		init_logger();
+		let mut wayvr = wayvr::WayVR::new()?;
+		let disp = wayvr.create_display(128, 128)?;
+
+		let format = (wl_shm::Format::Argb8888 as u32).to_string();
+		spawn_test_client(
+			&mut wayvr,
+			disp,
+			"tests::shm_client",
+			&[(SHM_CLIENT_FORMAT, format.as_str())],
+		)?;
+		wait_for_pixel(&mut wayvr, disp, [255, 128, 0, 255])?;
+
+		spawn_test_client(
+			&mut wayvr,
+			disp,
+			"tests::screencopy_client",
+			&[(SCREENCOPY_CLIENT_EXPECT, "ready")],
+		)?;
+
+		let status = wait_for_exit(&mut wayvr, disp)?;
+		assert!(status.success(), "Screencopy client exited with {}", status);
+		Ok(())
+	}
+
+	#[test]
+	fn screencopy_unauthorized() -> std::result::Result<(), Box<dyn std::error::Error>> {
+		init_logger();
+		let mut wayvr = wayvr::WayVR::new()?;
+		let disp = wayvr.create_display(128, 128)?;
+
+		// Connects, but doesn't belong to any display
+		spawn_test_client(
+			&mut wayvr,
+			disp,
+			"tests::screencopy_client",
+			&[
+				(SCREENCOPY_CLIENT_EXPECT, "failed"),
+				("WAYVR_DISPLAY_AUTH", "invalid"),
+			],
+		)?;
+
+		let status = wait_for_exit(&mut wayvr, disp)?;
+		assert!(status.success(), "Screencopy client exited with {}", status);
+		Ok(())
+	}
+
+	#[test]
+	fn gles_shm_display() -> std::result::Result<(), Box<dyn std::error::Error>> {
+		use crate::display;
+
+		init_logger();
+		let mut wayvr = wayvr::WayVR::new()?;
+
+		let mut config = display::DisplayConfig::new(64, 64);
+		config.output_mode = display::OutputMode::Shm;
+		let disp = wayvr.create_display_with_config(&config)?;
+
+		// Read back asynchronously, presented by one of the following ticks
+		let mut frame = None;
+		for _ in 0..100 {
+			frame = wayvr.tick_display(disp)?;
+			if frame.is_some() {
+				break;
+			}
+			wayvr.tick_finish()?;
+			std::thread::sleep(std::time::Duration::from_millis(10));
+		}
+		let frame = frame.expect("Read back frame wasn't presented");
+		assert!(frame.sync_fd.is_none());
+
+		// A single buffer is read back into two alternately
+		let outputs = wayvr
+			.get_swapchain_outputs(disp)?
+			.expect("Display should exist");
+		assert_eq!(outputs.len(), 2);
+
+		let display::DisplayOutput::Shm(shm) = &outputs[frame.buffer_index] else {
+			panic!("Forced shm displays should have shm output");
+		};
+		let pixels = unsafe { memmap2::Mmap::map(&shm.fd)? };
+		assert_eq!(pixels.len(), (shm.stride * shm.height) as usize);
+
+		// Empty displays are cleared to half-transparent white (premultiplied)
+		for pixel in pixels.chunks_exact(4) {
+			assert_eq!(pixel, &pixels[0..4]);
+		}
+		assert!((127..=128).contains(&pixels[3]));
+		assert_eq!(pixels[0], pixels[3]);
+
+		Ok(())
+	}
+
+	#[cfg(feature = "pixman")]
+	#[test]
+	fn pixman_empty_display() -> std::result::Result<(), Box<dyn std::error::Error>> {
+		use crate::display;
+
+		init_logger();
+		let mut wayvr = wayvr::WayVR::new_with_config(&wayvr::WayVRConfig {
+			renderer: wayvr::RendererType::Pixman,
+			..Default::default()
+		})?;
+
+		let disp = wayvr.create_display(64, 64)?;
+		let frame = wayvr
+			.tick_display(disp)?
+			.expect("First frame wasn't rendered");
+		assert_eq!(frame.buffer_index, 0);
+
+		// Nothing changed, nothing to render
+		assert!(wayvr.tick_display(disp)?.is_none());
+
+		let Some(display::DisplayOutput::Shm(shm)) = wayvr.get_display_output(disp)? else {
+			panic!("Pixman displays should have shm output");
+		};
+		let pixels = unsafe { memmap2::Mmap::map(&shm.fd)? };
+		assert_eq!(pixels.len(), (shm.stride * shm.height) as usize);
+
+		// Empty displays are cleared to half-transparent white (premultiplied)
+		for pixel in pixels.chunks_exact(4) {
+			assert_eq!(pixel, &pixels[0..4]);
+		}
+		assert!((127..=128).contains(&pixels[3]));
+		assert_eq!(pixels[0], pixels[3]);
+
+		Ok(())
+	}
 }
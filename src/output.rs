@@ -0,0 +1,185 @@
+use std::cell::RefCell;
+
+use smithay::{
+	output::{Mode, Output, PhysicalProperties, Scale, Subpixel},
+	reexports::wayland_server::{backend::GlobalId, protocol::wl_surface::WlSurface, DisplayHandle},
+	utils::Transform,
+	wayland::{
+		compositor::{send_surface_state, with_states, with_surface_tree_downward, TraversalAction},
+		fractional_scale::{with_fractional_scale, FractionalScaleHandler},
+		output::OutputHandler,
+	},
+};
+
+use crate::{comp::Application, display};
+
+// wl_output global of a single display, removed from the registry on drop
+pub struct WaylandOutput {
+	pub output: Output,
+	global: GlobalId,
+	dh: DisplayHandle,
+	refresh_rate: u32,
+	dpi: Option<f32>,
+}
+
+fn pixels_to_mm(pixels: u32, dpi: f32) -> i32 {
+	(pixels as f32 / dpi * 25.4).round() as i32
+}
+
+// Unknown physical size is reported as 0x0
+fn get_physical_size(width: u32, height: u32, dpi: Option<f32>) -> (i32, i32) {
+	dpi.filter(|dpi| *dpi > 0.0).map_or((0, 0), |dpi| {
+		(pixels_to_mm(width, dpi), pixels_to_mm(height, dpi))
+	})
+}
+
+impl WaylandOutput {
+	pub fn new(dh: &DisplayHandle, name: String, config: &display::DisplayConfig) -> Self {
+		// Clients see "make - model - name" as the description
+		let output = Output::new(
+			name,
+			PhysicalProperties {
+				size: get_physical_size(config.width, config.height, config.dpi).into(),
+				subpixel: Subpixel::Unknown,
+				make: String::from("WayVR"),
+				model: config
+					.description
+					.clone()
+					.unwrap_or_else(|| String::from("Virtual display")),
+			},
+		);
+
+		let global = output.create_global::<Application>(dh);
+
+		let mut res = Self {
+			output,
+			global,
+			dh: dh.clone(),
+			refresh_rate: config.refresh_rate,
+			dpi: config.dpi,
+		};
+		res.set_size(config.width, config.height);
+		res
+	}
+
+	// The physical size can't change once the output was created, it gets replaced by a new one.
+	// Clients see the old wl_output global go away, surfaces enter the new output with the next frame.
+	fn recreate(&mut self, physical_size: (i32, i32)) {
+		let properties = self.output.physical_properties();
+		let output = Output::new(
+			self.output.name(),
+			PhysicalProperties {
+				size: physical_size.into(),
+				..properties
+			},
+		);
+		output.change_current_state(None, None, Some(self.output.current_scale()), None);
+
+		self.dh.remove_global::<Application>(self.global.clone());
+		self.global = output.create_global::<Application>(&self.dh);
+		self.output = output;
+	}
+
+	// Integer-only clients get the scale rounded up
+	pub fn set_scale(&self, scale: f64) {
+		self
+			.output
+			.change_current_state(None, None, Some(Scale::Fractional(scale)), None);
+	}
+
+	pub fn set_size(&mut self, width: u32, height: u32) {
+		let physical_size = get_physical_size(width, height, self.dpi);
+		if self.output.physical_properties().size != physical_size.into() {
+			log::debug!(
+				"Physical size of {} changed to {}x{} mm",
+				self.output.name(),
+				physical_size.0,
+				physical_size.1
+			);
+			self.recreate(physical_size);
+		}
+
+		let mode = Mode {
+			size: (width as i32, height as i32).into(),
+			refresh: self.refresh_rate as i32,
+		};
+
+		self.output.change_current_state(
+			Some(mode),
+			Some(Transform::Normal),
+			None,
+			Some((0, 0).into()),
+		);
+		self.output.set_preferred(mode);
+	}
+}
+
+impl Drop for WaylandOutput {
+	fn drop(&mut self) {
+		self.dh.remove_global::<Application>(self.global.clone());
+	}
+}
+
+impl OutputHandler for Application {}
+
+impl FractionalScaleHandler for Application {
+	fn new_fractional_scale(&mut self, surface: WlSurface) {
+		// Surfaces which aren't on any output yet get their scale with the next frame
+		with_states(&surface, |states| {
+			let scale = states.data_map.get::<EnteredOutput>().and_then(|entered| {
+				entered
+					.0
+					.borrow()
+					.as_ref()
+					.map(|output| output.current_scale().fractional_scale())
+			});
+
+			if let Some(scale) = scale {
+				with_fractional_scale(states, |fractional| {
+					fractional.set_preferred_scale(scale);
+				});
+			}
+		});
+	}
+}
+
+// Output a surface was last told it's on
+#[derive(Default)]
+struct EnteredOutput(RefCell<Option<Output>>);
+
+// Sends wl_surface.enter for surfaces which weren't on this output yet, leaving the previous one.
+// Also sends the preferred scale of the output if it changed. Cheap enough to call every frame.
+pub fn enter_surface_tree(surface: &WlSurface, output: &Output) {
+	let scale = output.current_scale();
+
+	with_surface_tree_downward(
+		surface,
+		(),
+		|_, _, &()| TraversalAction::DoChildren(()),
+		|surface, states, &()| {
+			with_fractional_scale(states, |fractional| {
+				fractional.set_preferred_scale(scale.fractional_scale());
+			});
+			send_surface_state(surface, states, scale.integer_scale(), Transform::Normal);
+
+			states.data_map.insert_if_missing(EnteredOutput::default);
+			let mut entered = states
+				.data_map
+				.get::<EnteredOutput>()
+				.unwrap()
+				.0
+				.borrow_mut();
+
+			if entered.as_ref() == Some(output) {
+				return;
+			}
+
+			if let Some(previous) = entered.take() {
+				previous.leave(surface);
+			}
+			output.enter(surface);
+			*entered = Some(output.clone());
+		},
+		|_, _, &()| true,
+	);
+}
@@ -0,0 +1,408 @@
+// dma-buf modifier quirks, applied to the modifier list reported by the driver.
+//
+// One rule per line: <action> <modifier> [renderer=...] [vendor=...] [driver=...]
+//  - action: "force" (use only forced modifiers if all are supported), "prefer" (move to the front) or "ban" (remove)
+//  - renderer, vendor and driver are case-insensitive substrings of
+//    GL_RENDERER, GL_VENDOR and GL_VERSION, values with spaces can be quoted.
+//    There are no version comparisons: "driver=24.2" matches "4.6 (Compatibility Profile) Mesa 24.2.3",
+//    but also "Mesa 24.20.0", and can't express "Mesa 24.2 or newer".
+//  - "#" starts a comment
+//
+// Rules are read from the built-in list, the config file and the WAYVR_MODIFIER_QUIRKS
+// environment variable (rules separated by ";"), in this order. Later rules take precedence.
+
+use std::path::PathBuf;
+
+const BUILTIN_QUIRKS: &str = r#"
+# Passing all modifiers to the Vulkan dma-buf importer causes significant graphical corruption
+# due to invalid memory layout and tiling on AMD RX 7800 XT. Linear is also required.
+force 0x20000002086bf04 renderer=navi32
+force 0x0 renderer=navi32
+"#;
+
+const ENV_QUIRKS: &str = "WAYVR_MODIFIER_QUIRKS";
+const ENV_QUIRKS_FILE: &str = "WAYVR_MODIFIER_QUIRKS_FILE";
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum QuirkAction {
+	Force,
+	Prefer,
+	Ban,
+}
+
+#[derive(Clone, Debug)]
+struct Quirk {
+	action: QuirkAction,
+	modifier: u64,
+	renderer: Option<String>,
+	vendor: Option<String>,
+	driver: Option<String>,
+	source: String, // For logging
+}
+
+// Strings identifying the GPU and its driver
+#[derive(Clone, Debug)]
+pub struct GLInfo {
+	pub renderer: String,
+	pub vendor: String,
+	pub version: String,
+}
+
+fn contains_ignore_case(haystack: &str, needle: &str) -> bool {
+	haystack.to_lowercase().contains(&needle.to_lowercase())
+}
+
+impl Quirk {
+	fn matches(&self, info: &GLInfo) -> bool {
+		let matches = |pattern: &Option<String>, value: &str| {
+			pattern
+				.as_ref()
+				.map_or(true, |pattern| contains_ignore_case(value, pattern))
+		};
+
+		matches(&self.renderer, &info.renderer)
+			&& matches(&self.vendor, &info.vendor)
+			&& matches(&self.driver, &info.version)
+	}
+}
+
+// Splits a line by whitespace, keeping quoted values together
+fn tokenize(line: &str) -> Vec<String> {
+	let mut tokens = Vec::new();
+	let mut token = String::new();
+	let mut quoted = false;
+
+	for c in line.chars() {
+		match c {
+			'"' => quoted = !quoted,
+			c if c.is_whitespace() && !quoted => {
+				if !token.is_empty() {
+					tokens.push(std::mem::take(&mut token));
+				}
+			}
+			c => token.push(c),
+		}
+	}
+
+	if !token.is_empty() {
+		tokens.push(token);
+	}
+
+	tokens
+}
+
+fn parse_modifier(value: &str) -> anyhow::Result<u64> {
+	let res = match value.strip_prefix("0x") {
+		Some(hex) => u64::from_str_radix(hex, 16),
+		None => value.parse(),
+	};
+	res.map_err(|e| anyhow::anyhow!("Invalid modifier \"{}\": {}", value, e))
+}
+
+fn parse_rule(line: &str, source: String) -> anyhow::Result<Option<Quirk>> {
+	let line = line.split('#').next().unwrap_or_default();
+	let tokens = tokenize(line);
+	let Some((action, args)) = tokens.split_first() else {
+		return Ok(None);
+	};
+
+	let action = match action.as_str() {
+		"force" => QuirkAction::Force,
+		"prefer" => QuirkAction::Prefer,
+		"ban" => QuirkAction::Ban,
+		_ => anyhow::bail!("Unknown action \"{}\"", action),
+	};
+
+	let Some((modifier, keys)) = args.split_first() else {
+		anyhow::bail!("Missing modifier");
+	};
+
+	let mut quirk = Quirk {
+		action,
+		modifier: parse_modifier(modifier)?,
+		renderer: None,
+		vendor: None,
+		driver: None,
+		source,
+	};
+
+	for key in keys {
+		let Some((key, value)) = key.split_once('=') else {
+			anyhow::bail!("Expected key=value, got \"{}\"", key);
+		};
+
+		let value = Some(String::from(value));
+		match key {
+			"renderer" => quirk.renderer = value,
+			"vendor" => quirk.vendor = value,
+			"driver" => quirk.driver = value,
+			_ => anyhow::bail!("Unknown key \"{}\"", key),
+		}
+	}
+
+	Ok(Some(quirk))
+}
+
+fn parse_rules<'a>(lines: impl Iterator<Item = &'a str>, source: &str) -> Vec<Quirk> {
+	let mut quirks = Vec::new();
+
+	for (idx, line) in lines.enumerate() {
+		let line_source = format!("{}:{}", source, idx + 1);
+		match parse_rule(line, line_source.clone()) {
+			Ok(Some(quirk)) => quirks.push(quirk),
+			Ok(None) => {}
+			Err(e) => log::error!("Ignoring modifier quirk at {}: {}", line_source, e),
+		}
+	}
+
+	quirks
+}
+
+fn get_config_path() -> Option<PathBuf> {
+	if let Ok(path) = std::env::var(ENV_QUIRKS_FILE) {
+		return Some(PathBuf::from(path));
+	}
+
+	let config_dir = match std::env::var("XDG_CONFIG_HOME") {
+		Ok(dir) => PathBuf::from(dir),
+		Err(_) => PathBuf::from(std::env::var("HOME").ok()?).join(".config"),
+	};
+
+	Some(config_dir.join("wayvr").join("modifier_quirks.conf"))
+}
+
+// Quirks which apply to the current GPU
+pub struct ModifierQuirks {
+	quirks: Vec<Quirk>,
+}
+
+impl ModifierQuirks {
+	pub fn load(info: &GLInfo) -> Self {
+		log::debug!(
+			"Looking up modifier quirks for renderer \"{}\", vendor \"{}\", version \"{}\"",
+			info.renderer,
+			info.vendor,
+			info.version
+		);
+
+		let mut quirks = parse_rules(BUILTIN_QUIRKS.lines(), "built-in");
+
+		if let Some(path) = get_config_path() {
+			match std::fs::read_to_string(&path) {
+				Ok(data) => {
+					log::info!("Loading modifier quirks from {}", path.display());
+					quirks.extend(parse_rules(data.lines(), &path.to_string_lossy()));
+				}
+				Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+				Err(e) => log::error!("Failed to read {}: {}", path.display(), e),
+			}
+		}
+
+		if let Ok(rules) = std::env::var(ENV_QUIRKS) {
+			quirks.extend(parse_rules(rules.split(';'), ENV_QUIRKS));
+		}
+
+		quirks.retain(|quirk| {
+			let matches = quirk.matches(info);
+			if matches {
+				log::info!(
+					"Modifier quirk {} applies: {:?} {:#x}",
+					quirk.source,
+					quirk.action,
+					quirk.modifier
+				);
+			}
+			matches
+		});
+
+		Self { quirks }
+	}
+
+	pub fn is_banned(&self, modifier: u64) -> bool {
+		self
+			.quirks
+			.iter()
+			.any(|quirk| quirk.action == QuirkAction::Ban && quirk.modifier == modifier)
+	}
+
+	// Returns the modifiers to hand out to the host, in order of preference
+	pub fn apply(&self, modifiers: &[u64]) -> Vec<u64> {
+		let mut modifiers = modifiers.to_vec();
+		let mut forced: Vec<u64> = Vec::new();
+
+		for quirk in &self.quirks {
+			let modifier = quirk.modifier;
+			match quirk.action {
+				QuirkAction::Ban => {
+					modifiers.retain(|m| *m != modifier);
+					forced.retain(|m| *m != modifier);
+					log::warn!(
+						"Banning dma-buf modifier {:#x} ({})",
+						modifier,
+						quirk.source
+					);
+				}
+				QuirkAction::Prefer => {
+					if let Some(idx) = modifiers.iter().position(|m| *m == modifier) {
+						let modifier = modifiers.remove(idx);
+						modifiers.insert(0, modifier);
+						log::warn!(
+							"Preferring dma-buf modifier {:#x} ({})",
+							modifier,
+							quirk.source
+						);
+					}
+				}
+				QuirkAction::Force => {
+					if !forced.contains(&modifier) {
+						forced.push(modifier);
+					}
+				}
+			}
+		}
+
+		if forced.is_empty() {
+			return modifiers;
+		}
+
+		// Forced modifiers are only used if the driver supports all of them
+		if let Some(modifier) = forced.iter().find(|m| !modifiers.contains(m)) {
+			log::info!(
+				"Forced dma-buf modifier {:#x} isn't supported by the driver, ignoring forced modifiers",
+				modifier
+			);
+			return modifiers;
+		}
+
+		for modifier in &forced {
+			log::warn!("Using forced dma-buf modifier {:#x}", modifier);
+		}
+
+		forced
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn parse(line: &str) -> Quirk {
+		parse_rule(line, String::from("test")).unwrap().unwrap()
+	}
+
+	fn with_rules(rules: &str) -> ModifierQuirks {
+		ModifierQuirks {
+			quirks: parse_rules(rules.lines(), "test"),
+		}
+	}
+
+	fn gl_info() -> GLInfo {
+		GLInfo {
+			renderer: String::from("AMD Radeon RX 7800 XT (radeonsi, navi32, LLVM 18.1.8, DRM 3.57)"),
+			vendor: String::from("AMD"),
+			version: String::from("4.6 (Compatibility Profile) Mesa 24.2.3"),
+		}
+	}
+
+	#[test]
+	fn parse_actions() {
+		assert_eq!(parse("force 0x0").action, QuirkAction::Force);
+		assert_eq!(parse("prefer 0x0").action, QuirkAction::Prefer);
+		assert_eq!(parse("ban 0x0").action, QuirkAction::Ban);
+		assert_eq!(parse("ban 0x20000002086bf04").modifier, 0x20000002086bf04);
+		assert_eq!(parse("ban 16").modifier, 16);
+	}
+
+	#[test]
+	fn parse_keys() {
+		let quirk =
+			parse(r#"force 0x0 renderer=navi32 vendor="Advanced Micro Devices" driver=24.2 # comment"#);
+		assert_eq!(quirk.renderer.as_deref(), Some("navi32"));
+		assert_eq!(quirk.vendor.as_deref(), Some("Advanced Micro Devices"));
+		assert_eq!(quirk.driver.as_deref(), Some("24.2"));
+
+		let quirk = parse("prefer 0x0 vendor=amd");
+		assert_eq!(quirk.renderer, None);
+		assert_eq!(quirk.vendor.as_deref(), Some("amd"));
+		assert_eq!(quirk.driver, None);
+	}
+
+	#[test]
+	fn parse_empty() {
+		assert!(parse_rule("", String::new()).unwrap().is_none());
+		assert!(parse_rule("  # comment", String::new()).unwrap().is_none());
+	}
+
+	#[test]
+	fn parse_malformed() {
+		for line in [
+			"allow 0x0",
+			"ban",
+			"ban 0xzz",
+			"ban modifier",
+			"ban 0x0 navi32",
+			"ban 0x0 gpu=navi32",
+		] {
+			assert!(
+				parse_rule(line, String::new()).is_err(),
+				"\"{}\" should be rejected",
+				line
+			);
+		}
+
+		// Malformed lines are skipped, the rest is kept
+		assert_eq!(
+			parse_rules("ban\nban 0x0\nban 0xzz".lines(), "test").len(),
+			1
+		);
+	}
+
+	#[test]
+	fn matches() {
+		let info = gl_info();
+		assert!(parse("ban 0x0 renderer=NAVI32").matches(&info));
+		assert!(parse("ban 0x0 vendor=amd driver=\"Mesa 24.2\"").matches(&info));
+		assert!(!parse("ban 0x0 renderer=navi31").matches(&info));
+		assert!(!parse("ban 0x0 renderer=navi32 driver=\"Mesa 23\"").matches(&info));
+	}
+
+	#[test]
+	fn ban() {
+		let quirks = with_rules("ban 0x2");
+		assert_eq!(quirks.apply(&[0x1, 0x2, 0x3]), vec![0x1, 0x3]);
+		assert_eq!(quirks.apply(&[0x1, 0x3]), vec![0x1, 0x3]);
+		assert!(quirks.is_banned(0x2));
+		assert!(!quirks.is_banned(0x1));
+	}
+
+	#[test]
+	fn prefer() {
+		let quirks = with_rules("prefer 0x3");
+		assert_eq!(quirks.apply(&[0x1, 0x2, 0x3]), vec![0x3, 0x1, 0x2]);
+		assert_eq!(quirks.apply(&[0x1, 0x2]), vec![0x1, 0x2]);
+
+		// The last preferred modifier ends up in front
+		let quirks = with_rules("prefer 0x2\nprefer 0x3");
+		assert_eq!(quirks.apply(&[0x1, 0x2, 0x3]), vec![0x3, 0x2, 0x1]);
+	}
+
+	#[test]
+	fn force() {
+		let quirks = with_rules("force 0x3\nforce 0x1");
+		assert_eq!(quirks.apply(&[0x1, 0x2, 0x3]), vec![0x3, 0x1]);
+
+		// Not all forced modifiers are supported
+		assert_eq!(quirks.apply(&[0x1, 0x2]), vec![0x1, 0x2]);
+
+		// No forced modifier is supported
+		assert_eq!(quirks.apply(&[0x2, 0x4]), vec![0x2, 0x4]);
+		assert_eq!(quirks.apply(&[]), Vec::<u64>::new());
+	}
+
+	#[test]
+	fn force_banned() {
+		// Banned modifiers can't be forced
+		let quirks = with_rules("force 0x1\nforce 0x2\nban 0x2");
+		assert_eq!(quirks.apply(&[0x1, 0x2, 0x3]), vec![0x1]);
+	}
+}
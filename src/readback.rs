@@ -0,0 +1,252 @@
+use std::{fs::File, os::fd::OwnedFd};
+
+use smithay::backend::renderer::gles::ffi;
+
+#[cfg(feature = "pixman")]
+use smithay::reexports::pixman;
+
+// Display frame copied into shared memory, used if dma-buf export is unavailable.
+// Rows are stored top row first, the same layout as the dma-buf output: glReadPixels copies
+// texture memory in order, and the renderer already draws the top row into the first one.
+#[derive(Debug)]
+pub struct ShmData {
+	pub fd: OwnedFd, // memfd, mmap it with MAP_SHARED. try_clone duplicates it.
+	pub width: u32,
+	pub height: u32,
+	pub stride: u32,
+	pub fourcc: u32,
+}
+
+impl ShmData {
+	// Fails if the process ran out of fds
+	pub fn try_clone(&self) -> anyhow::Result<Self> {
+		Ok(Self {
+			fd: self
+				.fd
+				.try_clone()
+				.map_err(|e| anyhow::anyhow!("Failed to duplicate memfd: {}", e))?,
+			width: self.width,
+			height: self.height,
+			stride: self.stride,
+			fourcc: self.fourcc,
+		})
+	}
+}
+
+const FOURCC_ABGR8888: u32 = 0x34324241; // AB24, RGBA bytes in memory
+
+// Memfd-backed buffer which the host can mmap
+pub struct ShmBuffer {
+	width: u32,
+	height: u32,
+	memfd: File,
+	map: memmap2::MmapMut,
+}
+
+impl ShmBuffer {
+	pub fn new(width: u32, height: u32) -> anyhow::Result<Self> {
+		// The stride has to fit into ShmData
+		let size = width
+			.checked_mul(4)
+			.and_then(|stride| (stride as usize).checked_mul(height as usize))
+			.ok_or(anyhow::anyhow!(
+				"Buffer size {}x{} is too large",
+				width,
+				height
+			))?;
+
+		let memfd: File =
+			rustix::fs::memfd_create("wayvr-display", rustix::fs::MemfdFlags::CLOEXEC)?.into();
+		memfd.set_len(size as u64)?;
+		let map = unsafe { memmap2::MmapMut::map_mut(&memfd)? };
+
+		Ok(Self {
+			width,
+			height,
+			memfd,
+			map,
+		})
+	}
+
+	pub fn get_shm_data(&self) -> anyhow::Result<ShmData> {
+		let memfd = self
+			.memfd
+			.try_clone()
+			.map_err(|e| anyhow::anyhow!("Failed to duplicate memfd: {}", e))?;
+
+		Ok(ShmData {
+			fd: OwnedFd::from(memfd),
+			width: self.width,
+			height: self.height,
+			stride: self.width * 4,
+			fourcc: FOURCC_ABGR8888,
+		})
+	}
+
+	// Pixman image pointing to the buffer memory, borrowing the buffer keeps the mapping alive
+	#[cfg(feature = "pixman")]
+	pub fn create_pixman_image(&mut self) -> anyhow::Result<pixman::Image<'_, 'static>> {
+		let image = unsafe {
+			pixman::Image::from_raw_mut(
+				pixman::FormatCode::A8B8G8R8,
+				self.width as usize,
+				self.height as usize,
+				self.map.as_mut_ptr() as *mut u32,
+				(self.width * 4) as usize,
+				false,
+			)
+		};
+		image.map_err(|_| anyhow::anyhow!("Failed to create pixman image"))
+	}
+}
+
+// Asynchronous texture readback through a pixel buffer object
+pub struct Readback {
+	pbo: u32,
+	fbo: u32,
+	fence: Option<ffi::types::GLsync>,
+	buffer: ShmBuffer,
+	frame: u64, // Sequence number of the frame being read back
+}
+
+impl Readback {
+	pub fn new(gl: &ffi::Gles2, width: u32, height: u32) -> anyhow::Result<Self> {
+		let buffer = ShmBuffer::new(width, height)?;
+		let size = buffer.map.len();
+
+		let (pbo, fbo) = unsafe {
+			let mut pbo = 0;
+			gl.GenBuffers(1, &mut pbo);
+			gl.BindBuffer(ffi::PIXEL_PACK_BUFFER, pbo);
+			gl.BufferData(
+				ffi::PIXEL_PACK_BUFFER,
+				size as isize,
+				std::ptr::null(),
+				ffi::STREAM_READ,
+			);
+			gl.BindBuffer(ffi::PIXEL_PACK_BUFFER, 0);
+
+			let mut fbo = 0;
+			gl.GenFramebuffers(1, &mut fbo);
+			(pbo, fbo)
+		};
+
+		Ok(Self {
+			pbo,
+			fbo,
+			fence: None,
+			buffer,
+			frame: 0,
+		})
+	}
+
+	pub fn get_shm_data(&self) -> anyhow::Result<ShmData> {
+		self.buffer.get_shm_data()
+	}
+
+	pub fn is_pending(&self) -> bool {
+		self.fence.is_some()
+	}
+
+	pub fn get_frame(&self) -> u64 {
+		self.frame
+	}
+
+	// Queues a copy of the texture into the PBO, doesn't wait for it
+	pub fn start(&mut self, gl: &ffi::Gles2, tex_id: u32, frame: u64) {
+		self.frame = frame;
+
+		unsafe {
+			let mut prev_fbo = 0;
+			gl.GetIntegerv(ffi::READ_FRAMEBUFFER_BINDING, &mut prev_fbo);
+
+			gl.BindFramebuffer(ffi::READ_FRAMEBUFFER, self.fbo);
+			gl.FramebufferTexture2D(
+				ffi::READ_FRAMEBUFFER,
+				ffi::COLOR_ATTACHMENT0,
+				ffi::TEXTURE_2D,
+				tex_id,
+				0,
+			);
+
+			gl.BindBuffer(ffi::PIXEL_PACK_BUFFER, self.pbo);
+			gl.ReadPixels(
+				0,
+				0,
+				self.buffer.width as i32,
+				self.buffer.height as i32,
+				ffi::RGBA,
+				ffi::UNSIGNED_BYTE,
+				std::ptr::null_mut(),
+			);
+			gl.BindBuffer(ffi::PIXEL_PACK_BUFFER, 0);
+
+			gl.BindFramebuffer(ffi::READ_FRAMEBUFFER, prev_fbo as u32);
+
+			if let Some(fence) = self.fence.take() {
+				gl.DeleteSync(fence);
+			}
+			self.fence = Some(gl.FenceSync(ffi::SYNC_GPU_COMMANDS_COMPLETE, 0));
+			gl.Flush();
+		}
+	}
+
+	// Copies the PBO contents into shared memory if the readback has finished.
+	// Returns true if new data was copied.
+	pub fn poll(&mut self, gl: &ffi::Gles2) -> bool {
+		let Some(fence) = self.fence else {
+			return false;
+		};
+
+		unsafe {
+			let status = gl.ClientWaitSync(fence, 0, 0);
+			if status == ffi::TIMEOUT_EXPIRED {
+				return false;
+			}
+
+			gl.DeleteSync(fence);
+			self.fence = None;
+
+			if status == ffi::WAIT_FAILED {
+				log::error!("glClientWaitSync failed, dropping readback");
+				return false;
+			}
+
+			gl.BindBuffer(ffi::PIXEL_PACK_BUFFER, self.pbo);
+			let ptr = gl.MapBufferRange(
+				ffi::PIXEL_PACK_BUFFER,
+				0,
+				self.buffer.map.len() as isize,
+				ffi::MAP_READ_BIT,
+			);
+
+			let copied = !ptr.is_null();
+			if copied {
+				std::ptr::copy_nonoverlapping(
+					ptr as *const u8,
+					self.buffer.map.as_mut_ptr(),
+					self.buffer.map.len(),
+				);
+				gl.UnmapBuffer(ffi::PIXEL_PACK_BUFFER);
+			} else {
+				log::error!("glMapBufferRange failed");
+			}
+
+			gl.BindBuffer(ffi::PIXEL_PACK_BUFFER, 0);
+			copied
+		}
+	}
+
+	// GL objects can only be released with the context current
+	pub fn destroy(&mut self, gl: &ffi::Gles2) {
+		unsafe {
+			if let Some(fence) = self.fence.take() {
+				gl.DeleteSync(fence);
+			}
+			gl.DeleteBuffers(1, &self.pbo);
+			gl.DeleteFramebuffers(1, &self.fbo);
+		}
+		self.pbo = 0;
+		self.fbo = 0;
+	}
+}
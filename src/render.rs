@@ -0,0 +1,199 @@
+use std::rc::Rc;
+
+use smithay::{
+	backend::{
+		allocator::dmabuf::Dmabuf,
+		renderer::{
+			gles::{GlesRenderer, GlesTexture},
+			ImportDma, ImportMemWl,
+		},
+	},
+	reexports::wayland_server::{protocol::wl_shm, DisplayHandle},
+	wayland::dmabuf::{DmabufFeedbackBuilder, DmabufGlobal, DmabufState},
+};
+
+#[cfg(feature = "pixman")]
+use smithay::{
+	backend::renderer::{pixman::PixmanRenderer, Unbind},
+	reexports::pixman,
+};
+
+use crate::{capture, comp, display, egl_data, smithay_wrapper, swapchain, wayvr, window};
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum RendererType {
+	// OpenGL ES on top of EGL, required for dma-buf output
+	Gles,
+	// CPU compositing, works without any GPU. Displays always use shared memory output.
+	#[cfg(feature = "pixman")]
+	Pixman,
+}
+
+pub struct GlesContext {
+	pub renderer: GlesRenderer,
+	pub egl_data: Rc<egl_data::EGLData>,
+	pub dmabuf_formats: Rc<Vec<egl_data::DrmFormat>>,
+}
+
+// Composites the contents of every display
+pub enum WayVRRenderer {
+	Gles(GlesContext),
+	#[cfg(feature = "pixman")]
+	Pixman(PixmanRenderer),
+}
+
+impl WayVRRenderer {
+	pub fn new(config: &wayvr::WayVRConfig) -> anyhow::Result<Self> {
+		match config.renderer {
+			RendererType::Gles => {
+				let egl_data =
+					egl_data::EGLData::new(config.egl_platform, config.preferred_device.as_ref())?;
+				let smithay_display = smithay_wrapper::get_egl_display(&egl_data)?;
+				let smithay_context = smithay_wrapper::get_egl_context(&egl_data, &smithay_display)?;
+				let renderer = unsafe { GlesRenderer::new(smithay_context)? };
+
+				Ok(Self::Gles(GlesContext {
+					renderer,
+					egl_data: Rc::new(egl_data),
+					dmabuf_formats: Rc::new(config.dmabuf_formats.clone()),
+				}))
+			}
+			#[cfg(feature = "pixman")]
+			RendererType::Pixman => {
+				log::info!("Using Pixman software renderer");
+				Ok(Self::Pixman(PixmanRenderer::new()?))
+			}
+		}
+	}
+
+	pub fn create_swapchain(
+		&mut self,
+		width: u32,
+		height: u32,
+		buffer_count: u32,
+		output_mode: display::OutputMode,
+	) -> anyhow::Result<swapchain::Swapchain> {
+		match self {
+			Self::Gles(ctx) => swapchain::Swapchain::new(
+				&mut ctx.renderer,
+				ctx.egl_data.clone(),
+				width,
+				height,
+				buffer_count,
+				output_mode,
+				&ctx.dmabuf_formats,
+			),
+			#[cfg(feature = "pixman")]
+			Self::Pixman(_) => {
+				if output_mode == display::OutputMode::Dmabuf {
+					anyhow::bail!("dma-buf output is unavailable with the Pixman renderer");
+				}
+				swapchain::Swapchain::new_pixman(width, height, buffer_count)
+			}
+		}
+	}
+
+	// Releases renderer resources of the swapchain, call before dropping it
+	pub fn destroy_swapchain(&mut self, swapchain: &mut swapchain::Swapchain) {
+		match self {
+			Self::Gles(ctx) => swapchain.destroy(&mut ctx.renderer),
+			// Targets get unbound after every use, this only matters if that failed
+			#[cfg(feature = "pixman")]
+			Self::Pixman(renderer) => {
+				let _ = renderer.unbind();
+			}
+		}
+	}
+
+	// Blocks until all submitted rendering is done
+	pub fn finish(&mut self) -> anyhow::Result<()> {
+		match self {
+			Self::Gles(ctx) => {
+				ctx.renderer.with_context(|gl| unsafe {
+					gl.Flush();
+					gl.Finish();
+				})?;
+			}
+			// Renders synchronously
+			#[cfg(feature = "pixman")]
+			Self::Pixman(_) => {}
+		}
+		Ok(())
+	}
+
+	// Renders a single window into an offscreen buffer and reads it back
+	pub fn capture_window(&mut self, window: &window::Window) -> anyhow::Result<capture::RgbaImage> {
+		match self {
+			Self::Gles(ctx) => capture::render_window::<_, GlesTexture>(&mut ctx.renderer, window),
+			#[cfg(feature = "pixman")]
+			Self::Pixman(renderer) => {
+				capture::render_window::<_, pixman::Image<'static, 'static>>(renderer, window)
+			}
+		}
+	}
+
+	// zwp_linux_dmabuf_v1 with the formats the renderer can import.
+	// Version 4 feedback names the render node if it's known.
+	pub fn create_dmabuf_global(
+		&self,
+		dh: &DisplayHandle,
+		dmabuf_state: &mut DmabufState,
+	) -> Option<DmabufGlobal> {
+		let Self::Gles(ctx) = self else {
+			// Client dma-bufs would need a CPU mapping for every frame
+			return None;
+		};
+
+		let formats = ctx.renderer.dmabuf_formats();
+
+		if let Some(device) = &ctx.egl_data.device {
+			match DmabufFeedbackBuilder::new(device.render_dev_t, formats.clone()).build() {
+				Ok(feedback) => {
+					log::info!(
+						"Advertising linux-dmabuf feedback for {}",
+						device.render_node.display()
+					);
+					return Some(
+						dmabuf_state.create_global_with_default_feedback::<comp::Application>(dh, &feedback),
+					);
+				}
+				Err(e) => log::error!("Failed to build dma-buf feedback: {}", e),
+			}
+		}
+
+		log::warn!("Render node is unknown, advertising linux-dmabuf without feedback");
+		Some(dmabuf_state.create_global::<comp::Application>(dh, formats))
+	}
+
+	// Returns false if a client dma-buf can't be used for rendering
+	pub fn import_dmabuf(&mut self, dmabuf: &Dmabuf) -> bool {
+		match self {
+			Self::Gles(ctx) => match ctx.renderer.import_dmabuf(dmabuf, None) {
+				Ok(_) => true,
+				Err(e) => {
+					log::warn!("Failed to import client dma-buf: {}", e);
+					false
+				}
+			},
+			#[cfg(feature = "pixman")]
+			Self::Pixman(_) => false,
+		}
+	}
+
+	// Formats of client shm buffers the renderer can upload
+	pub fn shm_formats(&self) -> Vec<wl_shm::Format> {
+		match self {
+			Self::Gles(ctx) => ctx.renderer.shm_formats().collect(),
+			#[cfg(feature = "pixman")]
+			Self::Pixman(renderer) => renderer.shm_formats().collect(),
+		}
+	}
+
+	pub fn get_render_device(&self) -> Option<&egl_data::RenderDevice> {
+		match self {
+			Self::Gles(ctx) => ctx.egl_data.device.as_ref(),
+			#[cfg(feature = "pixman")]
+			Self::Pixman(_) => None,
+		}
+	}
+}
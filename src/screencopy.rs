@@ -0,0 +1,281 @@
+// zwlr_screencopy_manager_v1, lets clients capture the content of a display through its wl_output.
+// Copies are queued here and done by WayVR after the display got rendered.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use smithay::{
+	backend::allocator::{Buffer, Fourcc},
+	output::Output,
+	reexports::{
+		wayland_protocols_wlr::screencopy::v1::server::{
+			zwlr_screencopy_frame_v1::{self, ZwlrScreencopyFrameV1},
+			zwlr_screencopy_manager_v1::{self, ZwlrScreencopyManagerV1},
+		},
+		wayland_server::{
+			backend::GlobalId,
+			protocol::{wl_buffer::WlBuffer, wl_shm},
+			Client, DataInit, Dispatch, DisplayHandle, GlobalDispatch, New, Resource,
+		},
+	},
+	utils::{Physical, Rectangle},
+	wayland::{dmabuf::get_dmabuf, shm::with_buffer_contents_mut},
+};
+
+use crate::{capture, comp::Application};
+
+const VERSION: u32 = 3;
+
+pub struct ScreencopyState {
+	#[allow(dead_code)]
+	global: GlobalId,
+}
+
+impl ScreencopyState {
+	// dma-buf copies are only offered if the renderer can write into client dma-bufs
+	pub fn new(dh: &DisplayHandle, dmabuf: bool) -> Self {
+		let global = dh.create_global::<Application, ZwlrScreencopyManagerV1, _>(
+			VERSION,
+			ScreencopyGlobalData { dmabuf },
+		);
+		Self { global }
+	}
+}
+
+pub struct ScreencopyGlobalData {
+	dmabuf: bool,
+}
+
+pub struct ScreencopyFrameData {
+	output: Option<Output>,
+	region: Rectangle<i32, Physical>,
+	used: AtomicBool,
+}
+
+// A copy waiting for the display to be rendered
+pub struct ScreencopyRequest {
+	pub frame: ZwlrScreencopyFrameV1,
+	pub buffer: WlBuffer,
+	pub output: Output,
+	pub region: Rectangle<i32, Physical>,
+	pub with_damage: bool,
+}
+
+impl ScreencopyRequest {
+	pub fn fail(self) {
+		self.frame.failed();
+	}
+
+	pub fn complete(self) {
+		let time = rustix::time::clock_gettime(rustix::time::ClockId::Monotonic);
+		let tv_sec = time.tv_sec as u64;
+
+		self.frame.flags(zwlr_screencopy_frame_v1::Flags::empty());
+		if self.with_damage && self.frame.version() >= 2 {
+			// The whole region, there's no damage tracking per capture
+			self
+				.frame
+				.damage(0, 0, self.region.size.w as u32, self.region.size.h as u32);
+		}
+		self
+			.frame
+			.ready((tv_sec >> 32) as u32, tv_sec as u32, time.tv_nsec as u32);
+	}
+}
+
+fn check_buffer(buffer: &WlBuffer, region: &Rectangle<i32, Physical>) -> anyhow::Result<()> {
+	let (width, height) = (region.size.w, region.size.h);
+
+	if let Ok(dmabuf) = get_dmabuf(buffer) {
+		let size = dmabuf.size();
+		if size.w != width || size.h != height {
+			anyhow::bail!("dma-buf size doesn't match the frame");
+		}
+		if dmabuf.format().code != Fourcc::Argb8888 {
+			anyhow::bail!("Unsupported dma-buf format");
+		}
+		return Ok(());
+	}
+
+	let data = with_buffer_contents_mut(buffer, |_, _, data| data)
+		.map_err(|e| anyhow::anyhow!("Unsupported buffer: {:?}", e))?;
+
+	if data.format != wl_shm::Format::Argb8888 {
+		anyhow::bail!("Unsupported shm format");
+	}
+	if data.width != width || data.height != height || data.stride < width * 4 {
+		anyhow::bail!("Buffer size doesn't match the frame");
+	}
+
+	Ok(())
+}
+
+// Converts the captured region to ARGB8888 in the client shm buffer
+pub fn write_shm_buffer(
+	buffer: &WlBuffer,
+	image: &capture::RgbaImage,
+	region: &Rectangle<i32, Physical>,
+) -> anyhow::Result<()> {
+	let (x, y) = (region.loc.x as usize, region.loc.y as usize);
+	let (width, height) = (region.size.w as usize, region.size.h as usize);
+
+	if x + width > image.width as usize || y + height > image.height as usize {
+		anyhow::bail!("Region is out of display bounds");
+	}
+
+	with_buffer_contents_mut(buffer, |ptr, len, data| {
+		let offset = data.offset as usize;
+		let stride = data.stride as usize;
+		if height > 0 && offset + stride * (height - 1) + width * 4 > len {
+			anyhow::bail!("shm pool is too small");
+		}
+
+		let dst = unsafe { std::slice::from_raw_parts_mut(ptr, len) };
+
+		for row in 0..height {
+			let src_start = ((y + row) * image.width as usize + x) * 4;
+			let src = &image.data[src_start..src_start + width * 4];
+			let dst_start = offset + row * stride;
+			let dst = &mut dst[dst_start..dst_start + width * 4];
+
+			for (src, dst) in src.chunks_exact(4).zip(dst.chunks_exact_mut(4)) {
+				dst[0] = src[2];
+				dst[1] = src[1];
+				dst[2] = src[0];
+				dst[3] = src[3];
+			}
+		}
+
+		Ok(())
+	})
+	.map_err(|e| anyhow::anyhow!("Failed to access shm buffer: {:?}", e))?
+}
+
+impl GlobalDispatch<ZwlrScreencopyManagerV1, ScreencopyGlobalData> for Application {
+	fn bind(
+		_state: &mut Self,
+		_handle: &DisplayHandle,
+		_client: &Client,
+		resource: New<ZwlrScreencopyManagerV1>,
+		global_data: &ScreencopyGlobalData,
+		data_init: &mut DataInit<'_, Self>,
+	) {
+		data_init.init(resource, global_data.dmabuf);
+	}
+}
+
+impl Dispatch<ZwlrScreencopyManagerV1, bool> for Application {
+	fn request(
+		_state: &mut Self,
+		_client: &Client,
+		manager: &ZwlrScreencopyManagerV1,
+		request: zwlr_screencopy_manager_v1::Request,
+		dmabuf: &bool,
+		_dh: &DisplayHandle,
+		data_init: &mut DataInit<'_, Self>,
+	) {
+		let (frame, output, region) = match request {
+			zwlr_screencopy_manager_v1::Request::CaptureOutput { frame, output, .. } => {
+				(frame, output, None)
+			}
+			zwlr_screencopy_manager_v1::Request::CaptureOutputRegion {
+				frame,
+				output,
+				x,
+				y,
+				width,
+				height,
+				..
+			} => (
+				frame,
+				output,
+				Some(Rectangle::from_loc_and_size((x, y), (width, height))),
+			),
+			_ => return,
+		};
+
+		let output = Output::from_resource(&output);
+		let output_rect = output
+			.as_ref()
+			.and_then(|output| output.current_mode())
+			.map(|mode| Rectangle::from_loc_and_size((0, 0), mode.size));
+
+		// Regions are clamped to the display
+		let region = match (output_rect, region) {
+			(Some(output_rect), Some(region)) => region.intersection(output_rect),
+			(output_rect, None) => output_rect,
+			(None, _) => None,
+		};
+
+		let frame = data_init.init(
+			frame,
+			ScreencopyFrameData {
+				output,
+				region: region.unwrap_or_else(|| Rectangle::from_loc_and_size((0, 0), (0, 0))),
+				used: AtomicBool::new(false),
+			},
+		);
+
+		let Some(region) = region.filter(|region| !region.is_empty()) else {
+			// The display is gone or the region is outside of it
+			frame.failed();
+			return;
+		};
+
+		let (width, height) = (region.size.w as u32, region.size.h as u32);
+		frame.buffer(wl_shm::Format::Argb8888, width, height, width * 4);
+
+		if manager.version() >= 3 {
+			if *dmabuf {
+				frame.linux_dmabuf(Fourcc::Argb8888 as u32, width, height);
+			}
+			frame.buffer_done();
+		}
+	}
+}
+
+impl Dispatch<ZwlrScreencopyFrameV1, ScreencopyFrameData> for Application {
+	fn request(
+		state: &mut Self,
+		_client: &Client,
+		frame: &ZwlrScreencopyFrameV1,
+		request: zwlr_screencopy_frame_v1::Request,
+		data: &ScreencopyFrameData,
+		_dh: &DisplayHandle,
+		_data_init: &mut DataInit<'_, Self>,
+	) {
+		let (buffer, with_damage) = match request {
+			zwlr_screencopy_frame_v1::Request::Copy { buffer } => (buffer, false),
+			zwlr_screencopy_frame_v1::Request::CopyWithDamage { buffer } => (buffer, true),
+			_ => return,
+		};
+
+		if data.used.swap(true, Ordering::Relaxed) {
+			frame.post_error(
+				zwlr_screencopy_frame_v1::Error::AlreadyUsed,
+				"Frame was already used",
+			);
+			return;
+		}
+
+		let Some(output) = data.output.clone() else {
+			frame.failed();
+			return;
+		};
+
+		if let Err(e) = check_buffer(&buffer, &data.region) {
+			frame.post_error(
+				zwlr_screencopy_frame_v1::Error::InvalidBuffer,
+				e.to_string(),
+			);
+			return;
+		}
+
+		state.queue_screencopy.send(ScreencopyRequest {
+			frame: frame.clone(),
+			buffer,
+			output,
+			region: data.region,
+			with_damage,
+		});
+	}
+}
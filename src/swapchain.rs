@@ -0,0 +1,622 @@
+use std::rc::Rc;
+
+use smithay::{
+	backend::{
+		allocator::{
+			dmabuf::{AsDmabuf, Dmabuf},
+			gbm::{GbmAllocator, GbmBufferFlags, GbmDevice},
+			Allocator, Format, Fourcc, Modifier,
+		},
+		renderer::{
+			damage::OutputDamageTracker,
+			gles::{ffi, GlesRenderer, GlesTexture},
+			Bind, Unbind,
+		},
+	},
+	utils::Transform,
+};
+
+#[cfg(feature = "pixman")]
+use smithay::{backend::renderer::pixman::PixmanRenderer, reexports::pixman};
+
+use crate::{display, egl_data, quirks::ModifierQuirks, readback, smithay_wrapper};
+
+// How the rendered texture reaches the host
+enum TargetOutput {
+	// Texture exported through eglExportDMABUFImageMESA
+	Dmabuf {
+		egl_data: Rc<egl_data::EGLData>,
+		egl_image: khronos_egl::Image,
+		dmabuf_data: egl_data::DMAbufData,
+	},
+	// Buffer allocated with a format negotiated with the host
+	Negotiated {
+		dmabuf: Dmabuf,
+		dmabuf_data: egl_data::DMAbufData,
+	},
+	Shm(readback::Readback),
+	// Rendered into directly by the Pixman renderer
+	#[cfg(feature = "pixman")]
+	Pixman(readback::ShmBuffer),
+}
+
+// Host formats which can be rendered into, in the host's order of preference.
+// Modifier quirks are applied to the modifiers of every fourcc.
+fn negotiate_formats(
+	renderer: &GlesRenderer,
+	quirks: &ModifierQuirks,
+	host_formats: &[egl_data::DrmFormat],
+) -> Vec<Format> {
+	let render_formats = renderer.egl_context().dmabuf_render_formats();
+
+	let supported: Vec<Format> = host_formats
+		.iter()
+		.filter_map(|format| {
+			let format = Format {
+				code: Fourcc::try_from(format.fourcc).ok()?,
+				modifier: Modifier::from(format.modifier),
+			};
+			render_formats.contains(&format).then_some(format)
+		})
+		.collect();
+
+	let mut fourccs: Vec<Fourcc> = Vec::new();
+	for format in &supported {
+		if !fourccs.contains(&format.code) {
+			fourccs.push(format.code);
+		}
+	}
+
+	let mut formats = Vec::new();
+	for fourcc in fourccs {
+		let modifiers: Vec<u64> = supported
+			.iter()
+			.filter(|format| format.code == fourcc)
+			.map(|format| u64::from(format.modifier))
+			.collect();
+
+		formats.extend(quirks.apply(&modifiers).into_iter().map(|modifier| Format {
+			code: fourcc,
+			modifier: Modifier::from(modifier),
+		}));
+	}
+
+	formats
+}
+
+fn allocate_dmabuf(
+	gbm_device: &GbmDevice<std::fs::File>,
+	width: u32,
+	height: u32,
+	formats: &[Format],
+) -> anyhow::Result<Dmabuf> {
+	let mut allocator = GbmAllocator::new(gbm_device.clone(), GbmBufferFlags::RENDERING);
+
+	let mut fourccs: Vec<Fourcc> = Vec::new();
+	for format in formats {
+		if !fourccs.contains(&format.code) {
+			fourccs.push(format.code);
+		}
+	}
+
+	// GBM picks the best modifier out of the given ones
+	for fourcc in fourccs {
+		let modifiers: Vec<Modifier> = formats
+			.iter()
+			.filter(|format| format.code == fourcc)
+			.map(|format| format.modifier)
+			.collect();
+
+		match allocator.create_buffer(width, height, fourcc, &modifiers) {
+			Ok(buffer) => return Ok(buffer.export()?),
+			Err(e) => {
+				log::debug!("Failed to allocate {:?} buffer: {}", fourcc, e);
+			}
+		}
+	}
+
+	anyhow::bail!("No format supported by both the host and the renderer could be allocated");
+}
+
+fn get_negotiated_dmabuf_data(
+	dmabuf: &Dmabuf,
+	device: Option<egl_data::RenderDevice>,
+) -> anyhow::Result<egl_data::DMAbufData> {
+	let format = dmabuf.format();
+	let modifier = u64::from(format.modifier);
+
+	let mut planes = Vec::new();
+	for ((fd, stride), offset) in dmabuf.handles().zip(dmabuf.strides()).zip(dmabuf.offsets()) {
+		planes.push(egl_data::DMAbufPlane {
+			fd: fd.try_clone_to_owned()?,
+			stride: stride as i32,
+			offset: offset as i32,
+		});
+	}
+
+	Ok(egl_data::DMAbufData {
+		planes,
+		mod_info: egl_data::DMAbufModifierInfo {
+			modifiers: vec![modifier],
+			fourcc: format.code as u32,
+		},
+		modifier: (modifier != egl_data::DRM_FORMAT_MOD_INVALID).then_some(modifier),
+		device,
+	})
+}
+
+// Render buffer of a display, exported as dma-buf or read back into shared memory
+struct RenderTarget {
+	gles_texture: Option<GlesTexture>, // Only set for texture-backed outputs. Deleted on drop.
+	output: TargetOutput,
+
+	last_frame: u64, // 0 if never rendered into
+	held: bool,      // Sampled by the host, can't be rendered into
+}
+
+impl Drop for RenderTarget {
+	fn drop(&mut self) {
+		if let TargetOutput::Dmabuf {
+			egl_data,
+			egl_image,
+			..
+		} = &self.output
+		{
+			let _ = egl_data.egl.destroy_image(egl_data.display, *egl_image);
+		}
+	}
+}
+
+impl RenderTarget {
+	fn new_negotiated(
+		renderer: &mut GlesRenderer,
+		egl_data: Rc<egl_data::EGLData>,
+		width: u32,
+		height: u32,
+		host_formats: &[egl_data::DrmFormat],
+	) -> anyhow::Result<Self> {
+		let gbm_device = egl_data
+			.get_gbm_device()
+			.ok_or(anyhow::anyhow!("No GBM device to allocate dma-bufs with"))?;
+
+		let formats = negotiate_formats(renderer, &egl_data.quirks, host_formats);
+		if formats.is_empty() {
+			anyhow::bail!("The host doesn't support any dma-buf format the renderer can render into");
+		}
+
+		let dmabuf = allocate_dmabuf(gbm_device, width, height, &formats)?;
+		let dmabuf_data = get_negotiated_dmabuf_data(&dmabuf, egl_data.device.clone())?;
+
+		log::debug!(
+			"Allocated {}x{} dma-buf with format {:#x}, modifier {:#x}, {} plane(s)",
+			width,
+			height,
+			dmabuf_data.mod_info.fourcc,
+			dmabuf_data.mod_info.modifiers[0],
+			dmabuf_data.planes.len()
+		);
+
+		Ok(Self {
+			gles_texture: None,
+			output: TargetOutput::Negotiated {
+				dmabuf,
+				dmabuf_data,
+			},
+			last_frame: 0,
+			held: false,
+		})
+	}
+
+	// The driver exported the texture with a banned modifier. Allocates a buffer with one of the
+	// remaining modifiers instead, or LINEAR as a last resort.
+	fn new_unbanned(
+		renderer: &mut GlesRenderer,
+		egl_data: Rc<egl_data::EGLData>,
+		width: u32,
+		height: u32,
+		banned_modifier: u64,
+		dmabuf_data: egl_data::DMAbufData,
+	) -> anyhow::Result<Self> {
+		log::warn!(
+			"The driver exported a dma-buf with banned modifier {:#x}, allocating one instead",
+			banned_modifier
+		);
+
+		let fourcc = dmabuf_data.mod_info.fourcc;
+		let mut modifiers = dmabuf_data.mod_info.modifiers;
+		let linear = u64::from(Modifier::Linear);
+		if !modifiers.contains(&linear) && !egl_data.quirks.is_banned(linear) {
+			modifiers.push(linear);
+		}
+
+		let formats: Vec<egl_data::DrmFormat> = modifiers
+			.into_iter()
+			.map(|modifier| egl_data::DrmFormat { fourcc, modifier })
+			.collect();
+
+		Self::new_negotiated(renderer, egl_data, width, height, &formats)
+	}
+
+	fn new(
+		renderer: &mut GlesRenderer,
+		egl_data: Rc<egl_data::EGLData>,
+		width: u32,
+		height: u32,
+		shm: bool,
+		host_formats: &[egl_data::DrmFormat],
+	) -> anyhow::Result<Self> {
+		if !shm && !host_formats.is_empty() {
+			return Self::new_negotiated(renderer, egl_data, width, height, host_formats);
+		}
+
+		let tex_format = ffi::RGBA;
+		let internal_format = ffi::RGBA8;
+
+		let tex_id = renderer.with_context(|gl| {
+			smithay_wrapper::create_framebuffer_texture(gl, width, height, tex_format, internal_format)
+		})?;
+
+		let opaque = false;
+		let size = (width as i32, height as i32).into();
+		let gles_texture =
+			unsafe { GlesTexture::from_raw(renderer, Some(tex_format), opaque, tex_id, size) };
+
+		let output = if shm {
+			TargetOutput::Shm(renderer.with_context(|gl| readback::Readback::new(gl, width, height))??)
+		} else {
+			let egl_image = egl_data.create_egl_image(tex_id, width, height)?;
+			match egl_data.create_dmabuf_data(&egl_image) {
+				Ok(dmabuf_data) => match dmabuf_data.modifier {
+					Some(modifier) if egl_data.quirks.is_banned(modifier) => {
+						let _ = egl_data.egl.destroy_image(egl_data.display, egl_image);
+						return Self::new_unbanned(renderer, egl_data, width, height, modifier, dmabuf_data);
+					}
+					_ => TargetOutput::Dmabuf {
+						egl_data,
+						egl_image,
+						dmabuf_data,
+					},
+				},
+				Err(e) => {
+					let _ = egl_data.egl.destroy_image(egl_data.display, egl_image);
+					return Err(e);
+				}
+			}
+		};
+
+		Ok(Self {
+			gles_texture: Some(gles_texture),
+			output,
+			last_frame: 0,
+			held: false,
+		})
+	}
+
+	fn get_output(&self) -> anyhow::Result<display::DisplayOutput> {
+		Ok(match &self.output {
+			TargetOutput::Dmabuf { dmabuf_data, .. } | TargetOutput::Negotiated { dmabuf_data, .. } => {
+				display::DisplayOutput::Dmabuf(dmabuf_data.try_clone()?)
+			}
+			TargetOutput::Shm(readback) => display::DisplayOutput::Shm(readback.get_shm_data()?),
+			#[cfg(feature = "pixman")]
+			TargetOutput::Pixman(buffer) => display::DisplayOutput::Shm(buffer.get_shm_data()?),
+		})
+	}
+
+	fn is_readback_pending(&self) -> bool {
+		match &self.output {
+			TargetOutput::Shm(readback) => readback.is_pending(),
+			_ => false,
+		}
+	}
+
+	#[cfg(feature = "pixman")]
+	fn new_pixman(width: u32, height: u32) -> anyhow::Result<Self> {
+		Ok(Self {
+			gles_texture: None,
+			output: TargetOutput::Pixman(readback::ShmBuffer::new(width, height)?),
+			last_frame: 0,
+			held: false,
+		})
+	}
+
+	fn bind(&self, renderer: &mut GlesRenderer) -> anyhow::Result<()> {
+		match (&self.output, &self.gles_texture) {
+			(TargetOutput::Negotiated { dmabuf, .. }, _) => renderer.bind(dmabuf.clone())?,
+			(_, Some(gles_texture)) => renderer.bind(gles_texture.clone())?,
+			(_, None) => unreachable!(),
+		}
+		Ok(())
+	}
+}
+
+// One or more render targets of a single display.
+// With a single buffer, every frame is rendered into the same texture (no host synchronization).
+// Shm output gets two buffers instead, written into alternately, so the host never reads
+// the buffer being copied into.
+// With multiple buffers, presented buffers stay held by the host until it releases them.
+pub struct Swapchain {
+	targets: Vec<RenderTarget>,
+	current: Option<usize>,
+	frame_counter: u64,
+	presented_frame: u64, // Shm only: newest frame read back so far
+	shm: bool,
+	buffer_count: u32, // As requested, may be lower than the target count
+	pub damage_tracker: OutputDamageTracker,
+}
+
+pub const MAX_BUFFER_COUNT: u32 = 3;
+
+fn get_target_count(buffer_count: u32, shm: bool) -> usize {
+	if shm {
+		buffer_count.max(2) as usize
+	} else {
+		buffer_count as usize
+	}
+}
+
+impl Swapchain {
+	pub fn new(
+		renderer: &mut GlesRenderer,
+		egl_data: Rc<egl_data::EGLData>,
+		width: u32,
+		height: u32,
+		buffer_count: u32,
+		output_mode: display::OutputMode,
+		host_formats: &[egl_data::DrmFormat],
+	) -> anyhow::Result<Self> {
+		if buffer_count == 0 || buffer_count > MAX_BUFFER_COUNT {
+			anyhow::bail!(
+				"Invalid swapchain buffer count {} (expected 1 to {})",
+				buffer_count,
+				MAX_BUFFER_COUNT
+			);
+		}
+
+		let mut shm = output_mode == display::OutputMode::Shm;
+		// Cleared if no negotiated buffer can be allocated, the texture gets exported with EGL instead
+		let mut negotiate = !host_formats.is_empty();
+		let mut targets = Vec::new();
+
+		while targets.len() < get_target_count(buffer_count, shm) {
+			let formats = if negotiate { host_formats } else { &[] };
+			match RenderTarget::new(renderer, egl_data.clone(), width, height, shm, formats) {
+				Ok(target) => targets.push(target),
+				Err(e) if !shm && negotiate => {
+					log::warn!(
+						"dma-buf format negotiation failed ({}), exporting the texture instead",
+						e
+					);
+					negotiate = false;
+					Self::destroy_targets(renderer, &mut targets);
+				}
+				Err(e) if !shm && output_mode == display::OutputMode::Auto => {
+					log::warn!(
+						"dma-buf export unavailable ({}), falling back to CPU readback",
+						e
+					);
+					shm = true;
+					Self::destroy_targets(renderer, &mut targets);
+				}
+				Err(e) => {
+					Self::destroy_targets(renderer, &mut targets);
+					return Err(e);
+				}
+			}
+		}
+
+		Ok(Self {
+			targets,
+			current: None,
+			frame_counter: 0,
+			presented_frame: 0,
+			shm,
+			buffer_count,
+			damage_tracker: OutputDamageTracker::new(
+				(width as i32, height as i32),
+				1.0,
+				Transform::Normal,
+			),
+		})
+	}
+
+	#[cfg(feature = "pixman")]
+	pub fn new_pixman(width: u32, height: u32, buffer_count: u32) -> anyhow::Result<Self> {
+		if buffer_count == 0 || buffer_count > MAX_BUFFER_COUNT {
+			anyhow::bail!(
+				"Invalid swapchain buffer count {} (expected 1 to {})",
+				buffer_count,
+				MAX_BUFFER_COUNT
+			);
+		}
+
+		let targets = (0..get_target_count(buffer_count, true))
+			.map(|_| RenderTarget::new_pixman(width, height))
+			.collect::<anyhow::Result<Vec<_>>>()?;
+
+		Ok(Self {
+			targets,
+			current: None,
+			frame_counter: 0,
+			presented_frame: 0,
+			shm: true,
+			buffer_count,
+			damage_tracker: OutputDamageTracker::new(
+				(width as i32, height as i32),
+				1.0,
+				Transform::Normal,
+			),
+		})
+	}
+
+	fn destroy_targets(renderer: &mut GlesRenderer, targets: &mut Vec<RenderTarget>) {
+		let _ = renderer.with_context(|gl| {
+			for target in targets.iter_mut() {
+				if let TargetOutput::Shm(readback) = &mut target.output {
+					readback.destroy(gl);
+				}
+			}
+		});
+		targets.clear();
+
+		// Textures are deleted by the renderer once their last handle is dropped
+		if let Err(e) = renderer.cleanup_texture_cache() {
+			log::error!("Failed to clean up textures: {}", e);
+		}
+	}
+
+	// Releases GL objects which can't be freed on drop
+	pub fn destroy(&mut self, renderer: &mut GlesRenderer) {
+		// A bound target keeps its texture alive until the next bind
+		let _ = renderer.unbind();
+		Self::destroy_targets(renderer, &mut self.targets);
+	}
+
+	pub fn buffer_count(&self) -> u32 {
+		self.buffer_count
+	}
+
+	pub fn is_shm(&self) -> bool {
+		self.shm
+	}
+
+	// Returns a buffer index which can be rendered into and its age, None if all buffers are busy
+	pub fn acquire(&mut self) -> Option<(usize, usize)> {
+		// Counted per render attempt, even if the frame gets skipped later.
+		// This can only overestimate buffer age, which results in extra damage.
+		self.frame_counter += 1;
+
+		let idx = (0..self.targets.len()).find(|idx| {
+			let target = &self.targets[*idx];
+			if target.is_readback_pending() {
+				return false;
+			}
+			// A single buffer is always rendered into, no matter what the host does
+			self.targets.len() == 1 || (!target.held && Some(*idx) != self.current)
+		})?;
+
+		let target = &self.targets[idx];
+		let age = if target.last_frame == 0 {
+			0
+		} else {
+			(self.frame_counter - target.last_frame) as usize
+		};
+
+		Some((idx, age))
+	}
+
+	pub fn bind(&self, renderer: &mut GlesRenderer, idx: usize) -> anyhow::Result<()> {
+		self.targets[idx].bind(renderer)
+	}
+
+	// The renderer only accepts 'static targets, but the image points into the buffer memory.
+	// The caller has to unbind the renderer before the buffer gets dropped, and shouldn't keep it bound
+	// past the current frame: the image isn't tied to the swapchain lifetime.
+	#[cfg(feature = "pixman")]
+	pub unsafe fn bind_pixman(
+		&mut self,
+		renderer: &mut PixmanRenderer,
+		idx: usize,
+	) -> anyhow::Result<()> {
+		let TargetOutput::Pixman(buffer) = &mut self.targets[idx].output else {
+			anyhow::bail!("Swapchain wasn't created for the Pixman renderer");
+		};
+		let image = buffer.create_pixman_image()?;
+		let image =
+			std::mem::transmute::<pixman::Image<'_, 'static>, pixman::Image<'static, 'static>>(image);
+		renderer.bind(image)?;
+		Ok(())
+	}
+
+	// Called after the buffer got new content
+	pub fn mark_rendered(&mut self, idx: usize) {
+		self.targets[idx].last_frame = self.frame_counter;
+	}
+
+	// Marks the buffer as the newest frame, held by the host until released.
+	// Single buffered shm output is never held, the other buffer gets written into next.
+	pub fn present(&mut self, idx: usize) {
+		self.targets[idx].held = self.buffer_count > 1;
+		self.current = Some(idx);
+	}
+
+	// Shm only: starts copying the rendered buffer into shared memory
+	pub fn start_readback(&mut self, renderer: &mut GlesRenderer, idx: usize) -> anyhow::Result<()> {
+		let target = &mut self.targets[idx];
+		if let (TargetOutput::Shm(readback), Some(gles_texture)) =
+			(&mut target.output, &target.gles_texture)
+		{
+			let tex_id = gles_texture.tex_id();
+			let frame = target.last_frame;
+			renderer.with_context(|gl| readback.start(gl, tex_id, frame))?;
+		}
+		Ok(())
+	}
+
+	// Shm only: presents the newest frame whose readback has finished (if any).
+	// Older frames finishing later than a newer one are dropped.
+	pub fn poll_readbacks(&mut self, renderer: &mut GlesRenderer) -> anyhow::Result<Option<usize>> {
+		let mut finished: Option<(usize, u64)> = None;
+
+		renderer.with_context(|gl| {
+			for (idx, target) in self.targets.iter_mut().enumerate() {
+				let TargetOutput::Shm(readback) = &mut target.output else {
+					continue;
+				};
+
+				let frame = readback.get_frame();
+				if readback.poll(gl) && finished.map_or(true, |(_, newest)| frame > newest) {
+					finished = Some((idx, frame));
+				}
+			}
+		})?;
+
+		let Some((idx, frame)) = finished.filter(|(_, frame)| *frame > self.presented_frame) else {
+			return Ok(None);
+		};
+
+		self.presented_frame = frame;
+		self.present(idx);
+		Ok(Some(idx))
+	}
+
+	pub fn release(&mut self, idx: usize) {
+		if let Some(target) = self.targets.get_mut(idx) {
+			target.held = false;
+		}
+	}
+
+	pub fn get_current_index(&self) -> usize {
+		self.current.unwrap_or(0)
+	}
+
+	// Surfaces get rendered at this scale, forces a full redraw
+	pub fn set_scale(&mut self, width: u32, height: u32, scale: f64) {
+		self.damage_tracker =
+			OutputDamageTracker::new((width as i32, height as i32), scale, Transform::Normal);
+		self.reset_age();
+	}
+
+	// Forces a full redraw of every buffer
+	pub fn reset_age(&mut self) {
+		for target in &mut self.targets {
+			target.last_frame = 0;
+		}
+	}
+
+	pub fn get_output(&self, idx: usize) -> anyhow::Result<display::DisplayOutput> {
+		self
+			.targets
+			.get(idx)
+			.ok_or(anyhow::anyhow!("Invalid swapchain buffer index"))?
+			.get_output()
+	}
+
+	pub fn get_all_outputs(&self) -> anyhow::Result<Vec<display::DisplayOutput>> {
+		self
+			.targets
+			.iter()
+			.map(|target| target.get_output())
+			.collect()
+	}
+}
@@ -1,25 +1,46 @@
 use std::{cell::RefCell, rc::Rc};
 
 use smithay::{
-	backend::renderer::gles::GlesRenderer,
+	backend::allocator::dmabuf::Dmabuf,
 	input::SeatState,
-	reexports::wayland_server::{self, backend::ClientId},
+	reexports::{
+		wayland_protocols::xdg::shell::server::xdg_toplevel,
+		wayland_protocols_misc::server_decoration::server::org_kde_kwin_server_decoration_manager::Mode as KdeMode,
+		wayland_server::{
+			self,
+			backend::ClientId,
+			protocol::{wl_shm, wl_surface::WlSurface},
+		},
+	},
+	utils::{Logical, Point, Rectangle, Serial},
 	wayland::{
 		compositor,
+		dmabuf::{DmabufState, ImportNotifier},
+		fractional_scale::FractionalScaleManagerState,
+		output::OutputManagerState,
 		selection::data_device::DataDeviceState,
-		shell::xdg::{ToplevelSurface, XdgShellState},
+		shell::{
+			kde::decoration::KdeDecorationState,
+			xdg::{
+				decoration::XdgDecorationState, PopupSurface, PositionerState, ToplevelSurface,
+				XdgShellState,
+			},
+		},
 		shm::ShmState,
+		viewporter::ViewporterState,
 	},
 };
 
 pub use crate::egl_data;
+pub use crate::render::RendererType;
 
 use crate::{
-	client,
+	capture, client,
 	comp::Application,
+	decoration,
 	display::{self, DisplayVec},
 	event_queue::SyncEventQueue,
-	smithay_wrapper,
+	layout, output, render, screencopy,
 	time::get_millis,
 	window,
 };
@@ -39,13 +60,86 @@ impl WaylandEnv {
 #[allow(dead_code)]
 pub struct WayVR {
 	time_start: u64,
-	gles_renderer: GlesRenderer,
+	renderer: render::WayVRRenderer,
 	displays: display::DisplayVec,
 	manager: client::WayVRManager,
 	wm: Rc<RefCell<window::WindowManager>>,
-	egl_data: Rc<egl_data::EGLData>,
 
 	queue_new_toplevel: SyncEventQueue<(ClientId, ToplevelSurface)>,
+	queue_new_popup: SyncEventQueue<(PopupSurface, PositionerState)>,
+	queue_popup_grab: SyncEventQueue<PopupSurface>,
+	queue_popup_reposition: SyncEventQueue<(PopupSurface, PositionerState, u32)>,
+	queue_popup_destroyed: SyncEventQueue<PopupSurface>,
+	queue_toplevel_destroyed: SyncEventQueue<ToplevelSurface>,
+	queue_toplevel_mapping: SyncEventQueue<(WlSurface, bool)>,
+	queue_client_disconnected: SyncEventQueue<ClientId>,
+	queue_title_changed: SyncEventQueue<ToplevelSurface>,
+	queue_app_id_changed: SyncEventQueue<ToplevelSurface>,
+	queue_focus_changed: SyncEventQueue<Option<WlSurface>>,
+	queue_move_request: SyncEventQueue<(ToplevelSurface, Serial)>,
+	queue_resize_request: SyncEventQueue<(ToplevelSurface, Serial, xdg_toplevel::ResizeEdge)>,
+	queue_screencopy: SyncEventQueue<screencopy::ScreencopyRequest>,
+	queue_dmabuf_import: SyncEventQueue<(Dmabuf, ImportNotifier)>,
+	queue_decoration: SyncEventQueue<decoration::ClientDecoration>,
+
+	// Negotiated before their surface became a toplevel
+	pending_decorations: Vec<decoration::ClientDecoration>,
+	// Waiting for their display to be rendered
+	screencopy_requests: Vec<screencopy::ScreencopyRequest>,
+	// Used for unique wl_output names
+	output_counter: u32,
+
+	events: SyncEventQueue<WayVREvent>,
+}
+
+#[derive(Debug)]
+pub enum WayVREvent {
+	// Sent once the toplevel commits its first buffer, again if it gets remapped
+	WindowMapped {
+		display: display::DisplayHandle,
+		window: window::WindowHandle,
+	},
+	// Sent when the toplevel attaches a null buffer or gets destroyed, only after WindowMapped
+	WindowUnmapped {
+		display: display::DisplayHandle,
+		window: window::WindowHandle,
+	},
+	TitleChanged {
+		window: window::WindowHandle,
+		title: Option<String>,
+	},
+	AppIdChanged {
+		window: window::WindowHandle,
+		app_id: Option<String>,
+	},
+	// None if no window has keyboard focus
+	FocusChanged {
+		window: Option<window::WindowHandle>,
+	},
+	ClientConnected {
+		display: display::DisplayHandle,
+		pid: u32,
+	},
+	ClientDisconnected {
+		display: display::DisplayHandle,
+		pid: u32,
+	},
+	ProcessExited {
+		display: display::DisplayHandle,
+		pid: u32,
+		status: std::process::ExitStatus,
+	},
+	DisplayContentUpdated {
+		display: display::DisplayHandle,
+	},
+	// The previous dma-buf of this display is no longer valid, re-import it
+	DisplayResized {
+		display: display::DisplayHandle,
+		width: u32,
+		height: u32,
+		// Indexed by DisplayFrame::buffer_index
+		outputs: Vec<display::DisplayOutput>,
+	},
 }
 
 pub enum MouseIndex {
@@ -54,15 +148,65 @@ pub enum MouseIndex {
 	Right,
 }
 
+pub struct WayVRConfig {
+	pub renderer: RendererType,
+	pub egl_platform: egl_data::EGLPlatform,
+	// GPU to render on (render node path or dev_t), any if None
+	pub preferred_device: Option<egl_data::DeviceSelector>,
+	// Formats the host can import, in order of preference. Empty if unknown,
+	// in which case the driver picks the layout of exported dma-bufs.
+	pub dmabuf_formats: Vec<egl_data::DrmFormat>,
+	// Advertise zwlr_screencopy_manager_v1, letting connected clients record displays
+	pub screencopy: bool,
+}
+
+impl Default for WayVRConfig {
+	fn default() -> Self {
+		Self {
+			renderer: RendererType::Gles,
+			egl_platform: egl_data::EGLPlatform::Auto,
+			preferred_device: None,
+			dmabuf_formats: Vec::new(),
+			screencopy: true,
+		}
+	}
+}
+
 impl WayVR {
 	pub fn new() -> anyhow::Result<Self> {
+		Self::new_with_config(&WayVRConfig::default())
+	}
+
+	pub fn new_with_config(config: &WayVRConfig) -> anyhow::Result<Self> {
 		let display: wayland_server::Display<Application> = wayland_server::Display::new()?;
 		let dh = display.handle();
 		let compositor = compositor::CompositorState::new::<Application>(&dh);
 		let xdg_shell = XdgShellState::new::<Application>(&dh);
 		let mut seat_state = SeatState::new();
-		let shm = ShmState::new::<Application>(&dh, Vec::new());
 		let data_device = DataDeviceState::new::<Application>(&dh);
+		let output_manager = OutputManagerState::new_with_xdg_output::<Application>(&dh);
+		let fractional_scale = FractionalScaleManagerState::new::<Application>(&dh);
+		let viewporter = ViewporterState::new::<Application>(&dh);
+		let xdg_decoration = XdgDecorationState::new::<Application>(&dh);
+		let kde_decoration = KdeDecorationState::new::<Application>(&dh, KdeMode::Server);
+		let renderer = render::WayVRRenderer::new(config)?;
+
+		// ARGB8888 and XRGB8888 are always advertised
+		let extra_shm_formats: Vec<wl_shm::Format> = renderer
+			.shm_formats()
+			.into_iter()
+			.filter(|format| !matches!(format, wl_shm::Format::Argb8888 | wl_shm::Format::Xrgb8888))
+			.collect();
+		log::debug!("Additional shm formats: {:?}", extra_shm_formats);
+		let shm = ShmState::new::<Application>(&dh, extra_shm_formats);
+
+		let mut dmabuf_state = DmabufState::new();
+		let dmabuf_global = renderer.create_dmabuf_global(&dh, &mut dmabuf_state);
+
+		let screencopy = config.screencopy.then(|| {
+			let dmabuf = matches!(renderer, render::WayVRRenderer::Gles(_));
+			screencopy::ScreencopyState::new(&dh, dmabuf)
+		});
 		let mut seat = seat_state.new_wl_seat(&dh, "wayvr");
 
 		// TODO: Keyboard repeat delay and rate?
@@ -70,6 +214,22 @@ impl WayVR {
 		let seat_pointer = seat.add_pointer();
 
 		let queue_new_toplevel = SyncEventQueue::new();
+		let queue_new_popup = SyncEventQueue::new();
+		let queue_popup_grab = SyncEventQueue::new();
+		let queue_popup_reposition = SyncEventQueue::new();
+		let queue_popup_destroyed = SyncEventQueue::new();
+		let queue_toplevel_destroyed = SyncEventQueue::new();
+		let queue_toplevel_mapping = SyncEventQueue::new();
+		let queue_client_disconnected = SyncEventQueue::new();
+		let queue_title_changed = SyncEventQueue::new();
+		let queue_app_id_changed = SyncEventQueue::new();
+		let queue_focus_changed = SyncEventQueue::new();
+		let queue_move_request = SyncEventQueue::new();
+		let queue_resize_request = SyncEventQueue::new();
+		let queue_screencopy = SyncEventQueue::new();
+		let queue_dmabuf_import = SyncEventQueue::new();
+		let queue_decoration = SyncEventQueue::new();
+		let events = SyncEventQueue::new();
 
 		let state = Application {
 			compositor,
@@ -77,38 +237,129 @@ impl WayVR {
 			seat_state,
 			shm,
 			data_device,
+			output_manager,
+			fractional_scale,
+			viewporter,
+			xdg_decoration,
+			kde_decoration,
+			screencopy,
+			dmabuf_state,
+			dmabuf_global,
 			queue_new_toplevel: queue_new_toplevel.clone(),
+			queue_new_popup: queue_new_popup.clone(),
+			queue_popup_grab: queue_popup_grab.clone(),
+			queue_popup_reposition: queue_popup_reposition.clone(),
+			queue_popup_destroyed: queue_popup_destroyed.clone(),
+			queue_toplevel_destroyed: queue_toplevel_destroyed.clone(),
+			queue_toplevel_mapping: queue_toplevel_mapping.clone(),
+			queue_client_disconnected: queue_client_disconnected.clone(),
+			queue_title_changed: queue_title_changed.clone(),
+			queue_app_id_changed: queue_app_id_changed.clone(),
+			queue_focus_changed: queue_focus_changed.clone(),
+			queue_move_request: queue_move_request.clone(),
+			queue_resize_request: queue_resize_request.clone(),
+			queue_screencopy: queue_screencopy.clone(),
+			queue_dmabuf_import: queue_dmabuf_import.clone(),
+			queue_decoration: queue_decoration.clone(),
 		};
 
 		let time_start = get_millis();
-		let egl_data = egl_data::EGLData::new()?;
-		let smithay_display = smithay_wrapper::get_egl_display(&egl_data)?;
-		let smithay_context = smithay_wrapper::get_egl_context(&egl_data, &smithay_display)?;
-		let gles_renderer = unsafe { GlesRenderer::new(smithay_context)? };
 
 		Ok(Self {
-			gles_renderer,
+			renderer,
 			time_start,
-			manager: client::WayVRManager::new(state, display, seat_keyboard, seat_pointer)?,
+			manager: client::WayVRManager::new(
+				state,
+				display,
+				seat_keyboard,
+				seat_pointer,
+				events.clone(),
+			)?,
 			displays: DisplayVec::new(),
-			egl_data: Rc::new(egl_data),
 			wm: Rc::new(RefCell::new(window::WindowManager::new())),
 			queue_new_toplevel,
+			queue_new_popup,
+			queue_popup_grab,
+			queue_popup_reposition,
+			queue_popup_destroyed,
+			queue_toplevel_destroyed,
+			queue_toplevel_mapping,
+			queue_client_disconnected,
+			queue_title_changed,
+			queue_app_id_changed,
+			queue_focus_changed,
+			queue_move_request,
+			queue_resize_request,
+			queue_screencopy,
+			queue_dmabuf_import,
+			queue_decoration,
+			pending_decorations: Vec::new(),
+			screencopy_requests: Vec::new(),
+			output_counter: 0,
+			events,
 		})
 	}
 
-	pub fn tick_display(&mut self, display: display::DisplayHandle) -> anyhow::Result<()> {
+	// Returns a frame if the display texture has new content.
+	// Wait on its sync_fd before sampling the texture.
+	pub fn tick_display(
+		&mut self,
+		display_handle: display::DisplayHandle,
+	) -> anyhow::Result<Option<display::DisplayFrame>> {
 		// millis since the start of wayvr
 		let time_ms = get_millis() - self.time_start;
 
 		let display = self
 			.displays
-			.get(&display)
+			.get_mut(&display_handle)
 			.ok_or(anyhow::anyhow!("Invalid display handle"))?;
 
-		display.tick_render(&mut self.gles_renderer, time_ms)?;
+		let frame = if display.is_visible() {
+			display.tick_render(&mut self.renderer, time_ms)?
+		} else {
+			None
+		};
 
-		Ok(())
+		self.tick_screencopy(display_handle, frame.is_some());
+
+		if frame.is_some() {
+			self.events.send(WayVREvent::DisplayContentUpdated {
+				display: display_handle,
+			});
+		}
+
+		Ok(frame)
+	}
+
+	// Copies are done right away, copies with damage wait for a new frame
+	fn tick_screencopy(&mut self, display_handle: display::DisplayHandle, damaged: bool) {
+		let Some(display) = self.displays.get_mut(&display_handle) else {
+			return;
+		};
+
+		for request in std::mem::take(&mut self.screencopy_requests) {
+			if !request.frame.is_alive() {
+				continue;
+			}
+
+			if !display.has_wl_output(&request.output) || (request.with_damage && !damaged) {
+				self.screencopy_requests.push(request);
+				continue;
+			}
+
+			match display.copy_to_buffer(&mut self.renderer, &request.buffer, &request.region) {
+				Ok(()) => request.complete(),
+				Err(e) => {
+					log::error!("Screencopy failed: {}", e);
+					request.fail();
+				}
+			}
+		}
+	}
+
+	// Returns the next pending event, call this in a loop after tick_events
+	pub fn poll_event(&mut self) -> Option<WayVREvent> {
+		self.events.read()
 	}
 
 	pub fn tick_events(&mut self) -> anyhow::Result<()> {
@@ -130,31 +381,430 @@ impl WayVR {
 			}
 		}
 
-		self.manager.tick_wayland(&mut self.displays)
+		// Consumers only learn about windows once they have content
+		while let Some((surface, mapped)) = self.queue_toplevel_mapping.read() {
+			if mapped {
+				self.map_window(&surface);
+			} else {
+				self.unmap_window(&surface);
+			}
+		}
+
+		let mut decorations = std::mem::take(&mut self.pending_decorations);
+		while let Some(decoration) = self.queue_decoration.read() {
+			decorations.push(decoration);
+		}
+		for decoration in decorations {
+			self.set_window_decoration(decoration);
+		}
+
+		while let Some((popup, positioner)) = self.queue_new_popup.read() {
+			self.add_popup(popup, positioner);
+		}
+
+		while let Some((popup, positioner, token)) = self.queue_popup_reposition.read() {
+			self.reposition_popup(popup, positioner, token);
+		}
+
+		while let Some(popup) = self.queue_popup_grab.read() {
+			self.grab_popup(popup);
+		}
+
+		while let Some(popup) = self.queue_popup_destroyed.read() {
+			self.remove_popup(popup);
+		}
+
+		while let Some(toplevel) = self.queue_toplevel_destroyed.read() {
+			let window_handle = self.wm.borrow().find_window_handle(&toplevel);
+			if let Some(window_handle) = window_handle {
+				self.remove_window(window_handle);
+			}
+		}
+
+		while let Some(toplevel) = self.queue_title_changed.read() {
+			let wm = self.wm.borrow();
+			if let Some(window_handle) = wm.find_window_handle(&toplevel) {
+				if let Some(window) = wm.windows.get(&window_handle) {
+					self.events.send(WayVREvent::TitleChanged {
+						window: window_handle,
+						title: window.get_title(),
+					});
+				}
+			}
+		}
+
+		while let Some(toplevel) = self.queue_app_id_changed.read() {
+			let wm = self.wm.borrow();
+			if let Some(window_handle) = wm.find_window_handle(&toplevel) {
+				if let Some(window) = wm.windows.get(&window_handle) {
+					self.events.send(WayVREvent::AppIdChanged {
+						window: window_handle,
+						app_id: window.get_app_id(),
+					});
+				}
+			}
+		}
+
+		while let Some(client_id) = self.queue_client_disconnected.read() {
+			self.manager.remove_client(&client_id);
+
+			// Toplevels are usually destroyed along with the client, remove leftovers (if any)
+			let dead_windows = self.wm.borrow().dead_windows();
+			for window_handle in dead_windows {
+				self.remove_window(window_handle);
+			}
+		}
+
+		while let Some((toplevel, serial)) = self.queue_move_request.read() {
+			// Only allowed while a button is held, the grab ends on release
+			if !self.manager.seat_pointer.has_grab(serial) {
+				continue;
+			}
+
+			let window_handle = self.wm.borrow().find_window_handle(&toplevel);
+			if let Some(window_handle) = window_handle {
+				for cell in self.displays.vec.iter_mut().flatten() {
+					if cell.obj.has_window(window_handle) {
+						cell.obj.start_move(&self.manager, window_handle);
+					}
+				}
+			}
+		}
+
+		while let Some((toplevel, serial, edges)) = self.queue_resize_request.read() {
+			if !self.manager.seat_pointer.has_grab(serial) {
+				continue;
+			}
+
+			let window_handle = self.wm.borrow().find_window_handle(&toplevel);
+			if let Some(window_handle) = window_handle {
+				for cell in self.displays.vec.iter_mut().flatten() {
+					if cell.obj.has_window(window_handle) {
+						cell.obj.start_resize(&self.manager, window_handle, edges);
+					}
+				}
+			}
+		}
+
+		while let Some(request) = self.queue_screencopy.read() {
+			let authorized = request
+				.frame
+				.client()
+				.is_some_and(|client| self.manager.is_client_authorized(&client.id()));
+
+			if authorized {
+				self.screencopy_requests.push(request);
+			} else {
+				log::warn!("Rejecting screencopy from a client without a display auth key");
+				request.fail();
+			}
+		}
+
+		while let Some(focus) = self.queue_focus_changed.read() {
+			let window = focus.and_then(|surface| {
+				self
+					.wm
+					.borrow()
+					.find_surface_owner(&surface)
+					.map(|(handle, _, _)| handle)
+			});
+			self.events.send(WayVREvent::FocusChanged { window });
+		}
+
+		for (idx, cell) in self.displays.vec.iter_mut().enumerate() {
+			if let Some(cell) = cell {
+				let display_handle = display::DisplayVec::get_handle(cell, idx);
+				for (pid, status) in cell.obj.tick_processes() {
+					log::info!("Process {} exited with status {}", pid, status);
+					self.events.send(WayVREvent::ProcessExited {
+						display: display_handle,
+						pid,
+						status,
+					});
+				}
+			}
+		}
+
+		self.manager.tick_wayland(&mut self.displays)?;
+
+		// Requested while dispatching clients, answered right away so they don't wait a tick
+		let mut imported = false;
+		while let Some((dmabuf, notifier)) = self.queue_dmabuf_import.read() {
+			if self.renderer.import_dmabuf(&dmabuf) {
+				let _ = notifier.successful::<Application>();
+			} else {
+				notifier.failed();
+			}
+			imported = true;
+		}
+
+		// The replies were queued after tick_wayland flushed
+		if imported {
+			self.manager.flush_clients()?;
+		}
+
+		Ok(())
+	}
+
+	fn map_window(&mut self, surface: &WlSurface) {
+		let mut wm = self.wm.borrow_mut();
+		let Some(window_handle) = wm
+			.find_surface_owner(surface)
+			.map(|(window_handle, _, _)| window_handle)
+		else {
+			return;
+		};
+		let Some(window) = wm.windows.get_mut(&window_handle) else {
+			return;
+		};
+		if window.mapped {
+			return;
+		}
+
+		for (idx, cell) in self.displays.vec.iter().enumerate() {
+			if let Some(cell) = cell {
+				if cell.obj.has_window(window_handle) {
+					window.mapped = true;
+					self.events.send(WayVREvent::WindowMapped {
+						display: display::DisplayVec::get_handle(cell, idx),
+						window: window_handle,
+					});
+				}
+			}
+		}
+	}
+
+	// The toplevel attached a null buffer, it can get mapped again later
+	fn unmap_window(&mut self, surface: &WlSurface) {
+		let mut wm = self.wm.borrow_mut();
+		let Some(window_handle) = wm
+			.find_surface_owner(surface)
+			.map(|(window_handle, _, _)| window_handle)
+		else {
+			return;
+		};
+		let Some(window) = wm.windows.get_mut(&window_handle) else {
+			return;
+		};
+		if !window.mapped {
+			return;
+		}
+
+		window.mapped = false;
+		for (idx, cell) in self.displays.vec.iter().enumerate() {
+			if let Some(cell) = cell {
+				if cell.obj.has_window(window_handle) {
+					self.events.send(WayVREvent::WindowUnmapped {
+						display: display::DisplayVec::get_handle(cell, idx),
+						window: window_handle,
+					});
+				}
+			}
+		}
+	}
+
+	fn remove_window(&mut self, window_handle: window::WindowHandle) {
+		let mapped = self
+			.wm
+			.borrow()
+			.windows
+			.get(&window_handle)
+			.is_some_and(|window| window.mapped);
+		self.wm.borrow_mut().windows.remove(&window_handle);
+
+		for (idx, cell) in self.displays.vec.iter_mut().enumerate() {
+			if let Some(cell) = cell {
+				let display_handle = display::DisplayVec::get_handle(cell, idx);
+				let display = &mut cell.obj;
+				if display.remove_window(window_handle) {
+					// Windows which never got mapped weren't reported either
+					if mapped {
+						self.events.send(WayVREvent::WindowUnmapped {
+							display: display_handle,
+							window: window_handle,
+						});
+					}
+					display.restore_focus(&mut self.manager);
+				}
+			}
+		}
+	}
+
+	// Returns popup geometry relative to the parent's window geometry, constrained to the owning display.
+	// Also returns the position of the popup's window geometry relative to the toplevel origin.
+	fn position_popup(
+		&self,
+		popup: &PopupSurface,
+		positioner: &PositionerState,
+	) -> Option<(
+		window::WindowHandle,
+		Rectangle<i32, Logical>,
+		Point<i32, Logical>,
+	)> {
+		let parent = popup.get_parent_surface()?;
+		let wm = self.wm.borrow();
+		let (window_handle, parent_x, parent_y) = wm.find_surface_owner(&parent)?;
+		let window = wm.windows.get(&window_handle)?;
+
+		// The parent surface origin is known for both toplevels and popups.
+		// The positioner is relative to the parent's window geometry, not its surface.
+		let offset = window::get_geometry_offset(&parent);
+		let (parent_x, parent_y) = (parent_x + offset.x, parent_y + offset.y);
+
+		let display = self
+			.displays
+			.vec
+			.iter()
+			.flatten()
+			.map(|cell| &cell.obj)
+			.find(|disp| disp.has_window(window_handle));
+
+		let geometry = match display {
+			Some(display) => display.constrain_popup(window, parent_x, parent_y, positioner),
+			None => positioner.get_geometry(),
+		};
+
+		let pos = Point::from((parent_x + geometry.loc.x, parent_y + geometry.loc.y));
+		Some((window_handle, geometry, pos))
 	}
 
+	// Answered with the decoration mode of the display showing the window
+	fn set_window_decoration(&mut self, decoration: decoration::ClientDecoration) {
+		if !decoration.alive() {
+			return;
+		}
+
+		let owner = self.wm.borrow().find_surface_owner(decoration.wl_surface());
+		let display = owner.and_then(|(window_handle, _, _)| {
+			self
+				.displays
+				.vec
+				.iter_mut()
+				.flatten()
+				.map(|cell| &mut cell.obj)
+				.find(|disp| disp.has_window(window_handle))
+				.map(|disp| (window_handle, disp))
+		});
+
+		match display {
+			Some((window_handle, display)) => display.set_window_decoration(window_handle, decoration),
+			None => self.pending_decorations.push(decoration),
+		}
+	}
+
+	fn add_popup(&mut self, popup: PopupSurface, positioner: PositionerState) {
+		let Some((window_handle, geometry, pos)) = self.position_popup(&popup, &positioner) else {
+			log::warn!("Could not find parent window of a popup, ignoring");
+			popup.send_popup_done();
+			return;
+		};
+
+		popup.with_pending_state(|state| {
+			state.geometry = geometry;
+			state.positioner = positioner;
+		});
+
+		if let Err(e) = popup.send_configure() {
+			log::error!("Failed to configure popup: {:?}", e);
+		}
+
+		if let Some(window) = self.wm.borrow_mut().windows.get_mut(&window_handle) {
+			window.popups.push(window::Popup {
+				surface: popup,
+				geometry_x: pos.x,
+				geometry_y: pos.y,
+				size_x: geometry.size.w.max(0) as u32,
+				size_y: geometry.size.h.max(0) as u32,
+				grabbed: false,
+			});
+		}
+	}
+
+	fn reposition_popup(&mut self, popup: PopupSurface, positioner: PositionerState, token: u32) {
+		let Some((_, geometry, pos)) = self.position_popup(&popup, &positioner) else {
+			return;
+		};
+
+		popup.with_pending_state(|state| {
+			state.geometry = geometry;
+			state.positioner = positioner;
+		});
+		popup.send_repositioned(token);
+
+		if let Some((window, idx)) = self.wm.borrow_mut().find_popup_mut(&popup) {
+			let entry = &mut window.popups[idx];
+			entry.geometry_x = pos.x;
+			entry.geometry_y = pos.y;
+			entry.size_x = geometry.size.w.max(0) as u32;
+			entry.size_y = geometry.size.h.max(0) as u32;
+		}
+	}
+
+	fn grab_popup(&mut self, popup: PopupSurface) {
+		if let Some((window, idx)) = self.wm.borrow_mut().find_popup_mut(&popup) {
+			window.popups[idx].grabbed = true;
+		} else {
+			popup.send_popup_done();
+			return;
+		}
+
+		self.manager.seat_keyboard.set_focus(
+			&mut self.manager.state,
+			Some(popup.wl_surface().clone()),
+			self.manager.serial_counter.next_serial(),
+		);
+	}
+
+	fn remove_popup(&mut self, popup: PopupSurface) {
+		let mut wm = self.wm.borrow_mut();
+		let Some((window, idx)) = wm.find_popup_mut(&popup) else {
+			return;
+		};
+
+		// Child popups can't outlive their parent
+		while window.popups.len() > idx {
+			if let Some(child) = window.popups.pop() {
+				if child.surface != popup {
+					child.surface.send_popup_done();
+				}
+			}
+		}
+
+		let had_focus = self
+			.manager
+			.seat_keyboard
+			.current_focus()
+			.is_some_and(|focus| &focus == popup.wl_surface());
+
+		if had_focus {
+			self.manager.seat_keyboard.set_focus(
+				&mut self.manager.state,
+				Some(window.toplevel.wl_surface().clone()),
+				self.manager.serial_counter.next_serial(),
+			);
+		}
+	}
+
+	// Blocks until all rendering is done. Not needed if the host waits on DisplayFrame::sync_fd.
 	pub fn tick_finish(&mut self) -> anyhow::Result<()> {
-		self.gles_renderer.with_context(|gl| unsafe {
-			gl.Flush();
-			gl.Finish();
-		})?;
-		Ok(())
+		self.renderer.finish()
 	}
 
+	// Physical coordinates, in display pixels
 	pub fn send_mouse_move(&mut self, display: display::DisplayHandle, x: u32, y: u32) {
-		if let Some(display) = self.displays.get(&display) {
+		if let Some(display) = self.displays.get_mut(&display) {
 			display.send_mouse_move(&mut self.manager, x, y);
 		}
 	}
 
 	pub fn send_mouse_down(&mut self, display: display::DisplayHandle, index: MouseIndex) {
-		if let Some(display) = self.displays.get(&display) {
+		if let Some(display) = self.displays.get_mut(&display) {
 			display.send_mouse_down(&mut self.manager, index);
 		}
 	}
 
 	pub fn send_mouse_up(&mut self, display: display::DisplayHandle, index: MouseIndex) {
-		if let Some(display) = self.displays.get(&display) {
+		if let Some(display) = self.displays.get_mut(&display) {
 			display.send_mouse_up(&mut self.manager, index);
 		}
 	}
@@ -169,11 +819,173 @@ impl WayVR {
 		self.manager.send_key(virtual_key, down);
 	}
 
-	pub fn get_dmabuf_data(&self, display: display::DisplayHandle) -> Option<egl_data::DMAbufData> {
+	// None if the display doesn't exist or has shm output.
+	// Fails if the dma-buf fds can't be duplicated.
+	pub fn get_dmabuf_data(
+		&self,
+		display: display::DisplayHandle,
+	) -> anyhow::Result<Option<egl_data::DMAbufData>> {
+		let Some(display) = self.displays.get(&display) else {
+			return Ok(None);
+		};
+
+		match display.get_output()? {
+			display::DisplayOutput::Dmabuf(dmabuf_data) => Ok(Some(dmabuf_data)),
+			display::DisplayOutput::Shm(_) => Ok(None),
+		}
+	}
+
+	// GPU used for rendering, dma-bufs have to be imported on the same device
+	pub fn get_render_device(&self) -> Option<&egl_data::RenderDevice> {
+		self.renderer.get_render_device()
+	}
+
+	// Pixel formats of client shm buffers
+	pub fn get_shm_formats(&self) -> Vec<wl_shm::Format> {
+		self.renderer.shm_formats()
+	}
+
+	pub fn get_display_output(
+		&self,
+		display: display::DisplayHandle,
+	) -> anyhow::Result<Option<display::DisplayOutput>> {
+		self
+			.displays
+			.get(&display)
+			.map(|display| display.get_output())
+			.transpose()
+	}
+
+	// Output of every swapchain buffer, indexed by DisplayFrame::buffer_index
+	pub fn get_swapchain_outputs(
+		&self,
+		display: display::DisplayHandle,
+	) -> anyhow::Result<Option<Vec<display::DisplayOutput>>> {
+		self
+			.displays
+			.get(&display)
+			.map(|display| display.get_all_outputs())
+			.transpose()
+	}
+
+	// Pixels of the most recently presented frame
+	pub fn capture_display(
+		&mut self,
+		display: display::DisplayHandle,
+	) -> anyhow::Result<capture::RgbaImage> {
+		let Some(display) = self.displays.get_mut(&display) else {
+			anyhow::bail!("Display doesn't exist");
+		};
+		display.capture(&mut self.renderer)
+	}
+
+	// Renders the window on its own, without other windows overlapping it
+	pub fn capture_window(
+		&mut self,
+		window: window::WindowHandle,
+	) -> anyhow::Result<capture::RgbaImage> {
+		let wm = self.wm.borrow();
+		let Some(window) = wm.windows.get(&window) else {
+			anyhow::bail!("Window doesn't exist");
+		};
+		self.renderer.capture_window(window)
+	}
+
+	// Multi-buffered displays only: lets the compositor render into this buffer again
+	pub fn release_display_buffer(&mut self, display: display::DisplayHandle, buffer_index: usize) {
+		if let Some(display) = self.displays.get_mut(&display) {
+			display.release_buffer(buffer_index);
+		}
+	}
+
+	// Scales every window on the display, 2.0 makes content twice as large.
+	// Fractional values are supported by clients implementing wp_fractional_scale_v1.
+	pub fn set_display_scale(
+		&mut self,
+		display: display::DisplayHandle,
+		scale: f64,
+	) -> anyhow::Result<()> {
+		let display = self
+			.displays
+			.get_mut(&display)
+			.ok_or(anyhow::anyhow!("Invalid display handle"))?;
+		display.set_scale(scale)
+	}
+
+	pub fn get_display_scale(&self, display: display::DisplayHandle) -> Option<f64> {
 		self
 			.displays
 			.get(&display)
-			.map(|display| display.dmabuf_data.clone())
+			.map(|display| display.get_scale())
+	}
+
+	// Only affects clients which negotiate decorations, others keep drawing their own
+	pub fn set_display_decoration_mode(
+		&mut self,
+		display: display::DisplayHandle,
+		mode: display::DecorationMode,
+	) -> anyhow::Result<()> {
+		let display = self
+			.displays
+			.get_mut(&display)
+			.ok_or(anyhow::anyhow!("Invalid display handle"))?;
+		display.set_decoration_mode(mode);
+		Ok(())
+	}
+
+	pub fn get_display_decoration_mode(
+		&self,
+		display: display::DisplayHandle,
+	) -> Option<display::DecorationMode> {
+		self
+			.displays
+			.get(&display)
+			.map(|display| display.get_decoration_mode())
+	}
+
+	pub fn get_display_size(&self, display: display::DisplayHandle) -> Option<(u32, u32)> {
+		self
+			.displays
+			.get(&display)
+			.map(|display| display.get_size())
+	}
+
+	pub fn set_display_visible(&mut self, display: display::DisplayHandle, visible: bool) {
+		if let Some(display) = self.displays.get_mut(&display) {
+			display.set_visible(visible);
+		}
+	}
+
+	pub fn resize_display(
+		&mut self,
+		display_handle: display::DisplayHandle,
+		width: u32,
+		height: u32,
+	) -> anyhow::Result<()> {
+		// Their buffers have the old size, and the wl_output may get replaced
+		let size_changed = self
+			.displays
+			.get(&display_handle)
+			.is_some_and(|display| display.get_size() != (width, height));
+		if size_changed {
+			self.fail_screencopy_requests(display_handle);
+		}
+
+		let display = self
+			.displays
+			.get_mut(&display_handle)
+			.ok_or(anyhow::anyhow!("Invalid display handle"))?;
+
+		display.resize(&mut self.renderer, width, height)?;
+
+		self.events.send(WayVREvent::DisplayResized {
+			display: display_handle,
+			width,
+			height,
+			outputs: display.get_all_outputs()?,
+		});
+
+		Ok(())
 	}
 
 	pub fn create_display(
@@ -181,21 +993,70 @@ impl WayVR {
 		width: u32,
 		height: u32,
 	) -> anyhow::Result<display::DisplayHandle> {
+		self.create_display_with_config(&display::DisplayConfig::new(width, height))
+	}
+
+	pub fn create_display_with_config(
+		&mut self,
+		config: &display::DisplayConfig,
+	) -> anyhow::Result<display::DisplayHandle> {
+		self.output_counter += 1;
+		let wl_output = output::WaylandOutput::new(
+			&self.manager.display_handle(),
+			format!("WAYVR-{}", self.output_counter),
+			config,
+		);
+
 		let display = display::Display::new(
 			self.wm.clone(),
-			&mut self.gles_renderer,
-			self.egl_data.clone(),
+			&mut self.renderer,
 			self.manager.wayland_env.clone(),
-			width,
-			height,
+			wl_output,
+			config,
 		)?;
 		Ok(self.displays.add(display))
 	}
 
+	pub fn set_display_layout(
+		&mut self,
+		display: display::DisplayHandle,
+		layout: Box<dyn layout::Layout>,
+	) {
+		if let Some(display) = self.displays.get_mut(&display) {
+			display.set_layout(layout);
+		}
+	}
+
+	// Switches tabs in the maximized layout
+	pub fn activate_window(&mut self, display: display::DisplayHandle, window: window::WindowHandle) {
+		if let Some(display) = self.displays.get_mut(&display) {
+			display.activate_window(window);
+		}
+	}
+
 	pub fn destroy_display(&mut self, handle: display::DisplayHandle) {
+		self.fail_screencopy_requests(handle);
+		if let Some(display) = self.displays.get_mut(&handle) {
+			display.destroy(&mut self.renderer);
+		}
 		self.displays.remove(&handle);
 	}
 
+	// Fails pending screencopy requests of the display
+	fn fail_screencopy_requests(&mut self, handle: display::DisplayHandle) {
+		let Some(display) = self.displays.get(&handle) else {
+			return;
+		};
+
+		let (failed, pending): (Vec<_>, Vec<_>) = std::mem::take(&mut self.screencopy_requests)
+			.into_iter()
+			.partition(|request| display.has_wl_output(&request.output));
+		self.screencopy_requests = pending;
+		failed
+			.into_iter()
+			.for_each(screencopy::ScreencopyRequest::fail);
+	}
+
 	pub fn spawn_process(
 		&mut self,
 		display: display::DisplayHandle,
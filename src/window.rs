@@ -1,13 +1,62 @@
-use smithay::wayland::shell::xdg::ToplevelSurface;
+use smithay::{
+	reexports::wayland_server::protocol::wl_surface::WlSurface,
+	utils::{Logical, Point},
+	wayland::{
+		compositor::with_states,
+		shell::xdg::{PopupSurface, SurfaceCachedState, ToplevelSurface, XdgToplevelSurfaceData},
+	},
+};
 
 use crate::gen_id;
 
+// Offset of the xdg window geometry within the surface, e.g. where client-side shadows end.
+// Popups are positioned relative to it.
+pub fn get_geometry_offset(surface: &WlSurface) -> Point<i32, Logical> {
+	with_states(surface, |states| {
+		states
+			.cached_state
+			.get::<SurfaceCachedState>()
+			.current()
+			.geometry
+			.map_or_else(|| (0, 0).into(), |geometry| geometry.loc)
+	})
+}
+
+pub struct Popup {
+	pub surface: PopupSurface,
+	// Position of the popup's window geometry relative to the toplevel origin, as placed by the positioner
+	pub geometry_x: i32,
+	pub geometry_y: i32,
+	pub size_x: u32,
+	pub size_y: u32,
+	pub grabbed: bool,
+}
+
+impl Popup {
+	// Surface origin relative to the toplevel origin.
+	// The window geometry offset can change with every commit, so it isn't stored.
+	pub fn get_surface_pos(&self) -> (i32, i32) {
+		let offset = get_geometry_offset(self.surface.wl_surface());
+		(self.geometry_x - offset.x, self.geometry_y - offset.y)
+	}
+
+	// Shadows around the window geometry don't take input
+	pub fn contains(&self, x: i32, y: i32) -> bool {
+		x >= self.geometry_x
+			&& x < self.geometry_x + self.size_x as i32
+			&& y >= self.geometry_y
+			&& y < self.geometry_y + self.size_y as i32
+	}
+}
+
 pub struct Window {
 	pub pos_x: i32,
 	pub pos_y: i32,
 	pub size_x: u32,
 	pub size_y: u32,
 	pub toplevel: ToplevelSurface,
+	pub popups: Vec<Popup>, // Sorted from bottom to top
+	pub mapped: bool,       // Committed a buffer, WayVREvent::WindowMapped was sent
 }
 
 impl Window {
@@ -18,6 +67,8 @@ impl Window {
 			size_x: 0,
 			size_y: 0,
 			toplevel: toplevel.clone(),
+			popups: Vec::new(),
+			mapped: false,
 		}
 	}
 
@@ -36,6 +87,60 @@ impl Window {
 		self.size_x = size_x;
 		self.size_y = size_y;
 	}
+
+	pub fn contains(&self, x: i32, y: i32) -> bool {
+		x >= self.pos_x
+			&& x < self.pos_x + self.size_x as i32
+			&& y >= self.pos_y
+			&& y < self.pos_y + self.size_y as i32
+	}
+
+	pub fn get_title(&self) -> Option<String> {
+		with_states(self.toplevel.wl_surface(), |states| {
+			states
+				.data_map
+				.get::<XdgToplevelSurfaceData>()
+				.and_then(|data| data.lock().unwrap().title.clone())
+		})
+	}
+
+	pub fn get_app_id(&self) -> Option<String> {
+		with_states(self.toplevel.wl_surface(), |states| {
+			states
+				.data_map
+				.get::<XdgToplevelSurfaceData>()
+				.and_then(|data| data.lock().unwrap().app_id.clone())
+		})
+	}
+
+	// Returns (min_size, max_size) requested by the client, 0 means unconstrained
+	pub fn get_size_constraints(&self) -> ((u32, u32), (u32, u32)) {
+		with_states(self.toplevel.wl_surface(), |states| {
+			let mut cached = states.cached_state.get::<SurfaceCachedState>();
+			let current = cached.current();
+			(
+				(
+					current.min_size.w.max(0) as u32,
+					current.min_size.h.max(0) as u32,
+				),
+				(
+					current.max_size.w.max(0) as u32,
+					current.max_size.h.max(0) as u32,
+				),
+			)
+		})
+	}
+
+	pub fn has_grabbed_popup(&self) -> bool {
+		self.popups.iter().any(|popup| popup.grabbed)
+	}
+
+	// Sends popup_done to every popup of this window, topmost first
+	pub fn dismiss_popups(&mut self) {
+		while let Some(popup) = self.popups.pop() {
+			popup.surface.send_popup_done();
+		}
+	}
 }
 
 pub struct WindowManager {
@@ -61,6 +166,51 @@ impl WindowManager {
 		None
 	}
 
+	// Returns the window owning this surface (toplevel or one of its popups)
+	// and the surface origin relative to the toplevel origin
+	pub fn find_surface_owner(&self, surface: &WlSurface) -> Option<(WindowHandle, i32, i32)> {
+		for (idx, cell) in self.windows.vec.iter().enumerate() {
+			if let Some(cell) = cell {
+				let window = &cell.obj;
+				let handle = WindowVec::get_handle(cell, idx);
+
+				if window.toplevel.wl_surface() == surface {
+					return Some((handle, 0, 0));
+				}
+
+				for popup in &window.popups {
+					if popup.surface.wl_surface() == surface {
+						let (pos_x, pos_y) = popup.get_surface_pos();
+						return Some((handle, pos_x, pos_y));
+					}
+				}
+			}
+		}
+		None
+	}
+
+	pub fn find_popup_mut(&mut self, surface: &PopupSurface) -> Option<(&mut Window, usize)> {
+		for cell in self.windows.vec.iter_mut().flatten() {
+			let window = &mut cell.obj;
+			if let Some(idx) = window.popups.iter().position(|p| p.surface == *surface) {
+				return Some((window, idx));
+			}
+		}
+		None
+	}
+
+	pub fn dead_windows(&self) -> Vec<WindowHandle> {
+		let mut handles = Vec::new();
+		for (idx, cell) in self.windows.vec.iter().enumerate() {
+			if let Some(cell) = cell {
+				if !cell.obj.toplevel.alive() {
+					handles.push(WindowVec::get_handle(cell, idx));
+				}
+			}
+		}
+		handles
+	}
+
 	pub fn create_window(&mut self, toplevel: &ToplevelSurface) -> WindowHandle {
 		self.windows.add(Window::new(toplevel))
 	}